@@ -0,0 +1,668 @@
+//! Command line tool for reading logpeek log files, built with the `cli` feature.
+//!
+//! `logpeek tail [OPTIONS] <PATH>` prints the last entries of a log file, or of the newest file in a log directory,
+//! colored by level like the console output. With `--follow` it keeps printing new entries and moves on to the
+//! next file when the log is rotated. `logpeek merge [OPTIONS] <PATH>...` interleaves the entries of several log
+//! files or directories by their timestamps, tagged with the path they came from. Run `logpeek help` for the options.
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use log::{Level, LevelFilter};
+use regex::Regex;
+use time::UtcOffset;
+use time::format_description::{self, well_known::Rfc3339};
+
+use logpeek::config::{DateTimeFormat, Escaping, OutputFormat};
+use logpeek::query::{Query, QueryMatcher};
+use logpeek::reader::{Entry, EntryParser, LogReader};
+
+const USAGE: &str = "\
+Usage: logpeek tail [OPTIONS] <PATH>
+       logpeek merge [OPTIONS] <PATH>...
+
+tail prints the last entries of a log file, or of the newest log file named by date in a log directory.
+merge prints the entries of several log files or directories as one timeline, ordered by their timestamps in UTC
+and tagged with their path.
+
+Options:
+  -f, --follow              tail: Keep printing new entries, following rotations to the newest file
+  -n, --lines <N>           tail: Number of entries to print first [default: 10]
+  -l, --level <LEVEL>       Only entries of this level and more severe ones (error, warn, info, debug, trace)
+  -t, --target <PATTERN>    Only entries whose target matches the pattern, can be repeated
+  -e, --regex <REGEX>       Only entries whose message matches the regular expression
+      --color <WHEN>        Color the output by level: auto, always or never [default: auto]
+  -h, --help                Print this help
+
+File options, which apply to the paths after them when merging:
+      --format <FORMAT>     The format of the files: plain, logfmt or gelf [default: plain]
+      --time-format <FMT>   The DateTimeFormat of the files: iso8601, rfc3339, rfc2822 or a custom
+                            format description [default: iso8601]
+      --offset <OFFSET>     The UTC offset of timestamps written without one, e.g. +02:00 [default: +00:00]
+      --escaping <MODE>     The Escaping of plain text files: escape, indent, none or strip [default: escape]
+      --continuation <MARKER>
+                            The prefix of continuation lines, for files written with Escaping::Marker
+                            [default: four spaces]
+
+Example:
+  logpeek merge api/logs --time-format rfc2822 --offset +02:00 worker/logs
+";
+
+/// How often a followed file is checked for new entries.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+/// The options shared by all subcommands.
+struct Filters {
+    query: Query,
+    regex: Option<Regex>,
+    color: Color,
+}
+
+impl Default for Filters {
+    fn default() -> Self {
+        Filters { query: Query::default(), regex: None, color: Color::Auto }
+    }
+}
+
+impl Filters {
+    /// Handles `arg` if it's one of the shared options. Returns `false` if it isn't.
+    fn parse_option(&mut self, arg: &str, value: &mut impl FnMut() -> Result<String, String>) -> Result<bool, String> {
+        match arg {
+            "-l" | "--level" => {
+                self.query.min_level = LevelFilter::from_str(&value()?).map_err(|_| String::from("unknown level"))?;
+            },
+            "-t" | "--target" => self.query.targets.push(value()?),
+            "-e" | "--regex" => self.regex = Some(Regex::new(&value()?).map_err(|err| err.to_string())?),
+            "--color" => self.color = match value()?.as_str() {
+                "auto" => Color::Auto,
+                "always" => Color::Always,
+                "never" => Color::Never,
+                other => return Err(format!("unknown --color value {:?}", other)),
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// How the files of a path were written.
+struct FileOptions {
+    format: OutputFormat,
+    datetime_format: DateTimeFormat,
+    offset: UtcOffset,
+    /// Whether the files were written with `Escaping::Escape`, the other modes are read the same way.
+    escaped: bool,
+    continuation: Option<String>,
+}
+
+impl Default for FileOptions {
+    fn default() -> Self {
+        FileOptions {
+            format: OutputFormat::PlainText,
+            datetime_format: DateTimeFormat::ISO8601,
+            offset: UtcOffset::UTC,
+            escaped: true,
+            continuation: None,
+        }
+    }
+}
+
+impl FileOptions {
+    /// Handles `arg` if it's one of the file options. Returns `false` if it isn't.
+    fn parse_option(&mut self, arg: &str, value: &mut impl FnMut() -> Result<String, String>) -> Result<bool, String> {
+        match arg {
+            "--format" => self.format = parse_format(&value()?)?,
+            "--time-format" => self.datetime_format = parse_datetime_format(value()?),
+            "--offset" => self.offset = parse_offset(&value()?)?,
+            "--escaping" => self.escaped = match value()?.as_str() {
+                "escape" => true,
+                "indent" | "none" | "strip" => false,
+                other => return Err(format!("unknown --escaping value {:?}", other)),
+            },
+            "--continuation" => self.continuation = Some(value()?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn reader(&self) -> LogReader {
+        let reader = LogReader::new(self.format, &self.datetime_format)
+            .assume_offset(self.offset)
+            .escaping(if self.escaped { &Escaping::Escape } else { &Escaping::None });
+        match &self.continuation {
+            Some(marker) => reader.continuation_marker(marker),
+            None => reader,
+        }
+    }
+}
+
+struct TailArgs {
+    path: PathBuf,
+    follow: bool,
+    lines: usize,
+    filters: Filters,
+    file_options: FileOptions,
+}
+
+impl TailArgs {
+    /// Returns `None` if the help was asked for.
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<TailArgs>, String> {
+        let mut path = None;
+        let mut follow = false;
+        let mut lines = 10;
+        let mut filters = Filters::default();
+        let mut file_options = FileOptions::default();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-f" | "--follow" => follow = true,
+                "-n" | "--lines" => lines = value()?.parse().map_err(|_| String::from("--lines needs a number"))?,
+                _ if filters.parse_option(&arg, &mut value)? || file_options.parse_option(&arg, &mut value)? => {},
+                _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+                _ if path.is_none() => path = Some(PathBuf::from(arg)),
+                _ => return Err(String::from("only one path can be given")),
+            }
+        }
+
+        Ok(Some(TailArgs {
+            path: path.ok_or_else(|| String::from("missing the path of a log file or directory"))?,
+            follow,
+            lines,
+            filters,
+            file_options,
+        }))
+    }
+}
+
+struct MergeArgs {
+    sources: Vec<(PathBuf, FileOptions)>,
+    filters: Filters,
+}
+
+impl MergeArgs {
+    /// Returns `None` if the help was asked for.
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<MergeArgs>, String> {
+        let mut sources = Vec::new();
+        let mut filters = Filters::default();
+        let mut file_options = FileOptions::default();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                _ if filters.parse_option(&arg, &mut value)? || file_options.parse_option(&arg, &mut value)? => {},
+                _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+                _ => {
+                    let options = FileOptions {
+                        format: file_options.format,
+                        datetime_format: copy_datetime_format(&file_options.datetime_format),
+                        offset: file_options.offset,
+                        escaped: file_options.escaped,
+                        continuation: file_options.continuation.clone(),
+                    };
+                    sources.push((PathBuf::from(arg), options));
+                },
+            }
+        }
+
+        if sources.is_empty() {
+            return Err(String::from("missing the paths of the log files or directories to merge"));
+        }
+        Ok(Some(MergeArgs { sources, filters }))
+    }
+}
+
+fn parse_format(format: &str) -> Result<OutputFormat, String> {
+    match format {
+        "plain" => Ok(OutputFormat::PlainText),
+        "logfmt" => Ok(OutputFormat::Logfmt),
+        "gelf" => Ok(OutputFormat::Gelf),
+        other => Err(format!("unknown format {:?}", other)),
+    }
+}
+
+/// `DateTimeFormat` isn't `Clone`, but all of its variants are.
+fn copy_datetime_format(format: &DateTimeFormat) -> DateTimeFormat {
+    match format {
+        DateTimeFormat::ISO8601 => DateTimeFormat::ISO8601,
+        DateTimeFormat::RFC3339 => DateTimeFormat::RFC3339,
+        DateTimeFormat::RFC2822 => DateTimeFormat::RFC2822,
+        DateTimeFormat::Custom(format) => DateTimeFormat::Custom(format),
+    }
+}
+
+fn parse_offset(offset: &str) -> Result<UtcOffset, String> {
+    if offset.eq_ignore_ascii_case("z") || offset.eq_ignore_ascii_case("utc") {
+        return Ok(UtcOffset::UTC);
+    }
+
+    let format = format_description::parse_borrowed::<1>("[offset_hour sign:mandatory]:[offset_minute]")
+        .map_err(|err| err.to_string())?;
+    UtcOffset::parse(offset, &format).map_err(|_| format!("invalid offset {:?}, expected e.g. +02:00", offset))
+}
+
+fn parse_datetime_format(format: String) -> DateTimeFormat {
+    match format.to_ascii_lowercase().as_str() {
+        "iso8601" => DateTimeFormat::ISO8601,
+        "rfc3339" => DateTimeFormat::RFC3339,
+        "rfc2822" => DateTimeFormat::RFC2822,
+        // The config holds custom formats as `&'static str`, the string lives until the process exits anyway
+        _ => DateTimeFormat::Custom(Box::leak(format.into_boxed_str())),
+    }
+}
+
+/// Lays an entry out like the default `LineTemplate`, with the key-values after the message.
+/// With `utc`, the time is shown in UTC instead of the way it was written.
+fn render(entry: &Entry, format: OutputFormat, utc: bool) -> String {
+    let mut line = match (format, entry.timestamp) {
+        // GELF timestamps are seconds since the epoch
+        (_, Some(timestamp)) if utc || format == OutputFormat::Gelf => {
+            timestamp.to_offset(UtcOffset::UTC).format(&Rfc3339).unwrap_or_else(|_| entry.time.clone())
+        },
+        _ => entry.time.clone(),
+    };
+
+    line.push_str(&format!(" {} {}", entry.level, entry.target));
+    if let Some(module_path) = &entry.module_path {
+        line.push_str(&format!(" {}", module_path));
+    }
+    if let (Some(file), Some(line_number)) = (&entry.file, entry.line) {
+        line.push_str(&format!(" {}:{}", file, line_number));
+    }
+    match (&entry.thread_name, &entry.thread_id) {
+        (Some(name), Some(id)) => line.push_str(&format!(" [{}#{}]", name, id)),
+        (Some(name), None) => line.push_str(&format!(" [{}]", name)),
+        (None, Some(id)) => line.push_str(&format!(" [{}]", id)),
+        (None, None) => {},
+    }
+    if let Some(process_id) = entry.process_id {
+        line.push_str(&format!(" pid={}", process_id));
+    }
+
+    line.push_str(" - ");
+    line.push_str(&entry.message.replace('\n', "\n    "));
+    for (key, value) in &entry.key_values {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+    line.push('\n');
+    line
+}
+
+/// Returns the newest log file named by `OutputFileName::AutoGenerate` in `dir`. Other `.log` files, like the
+/// files of routing rules, are never the newest one.
+fn newest_file(dir: &Path) -> io::Result<Option<PathBuf>> {
+    Ok(logpeek::reader::log_files(dir)?.pop())
+}
+
+/// Returns the file that replaced `path` if the log in `dir` was rotated, i.e. there's a newer log file.
+fn rotated(dir: Option<&Path>, path: &Path) -> io::Result<Option<PathBuf>> {
+    let Some(dir) = dir else { return Ok(None) };
+    Ok(newest_file(dir)?.filter(|newest| newest.as_path() > path))
+}
+
+/// Reads the entries of a file that may still be written to.
+struct Follower<'a> {
+    file: BufReader<File>,
+    parser: EntryParser<'a>,
+    /// A line that hasn't been written completely yet.
+    partial: String,
+}
+
+impl<'a> Follower<'a> {
+    fn open(path: &Path, reader: &'a LogReader) -> io::Result<Follower<'a>> {
+        Ok(Follower { file: BufReader::new(File::open(path)?), parser: EntryParser::new(reader), partial: String::new() })
+    }
+
+    /// Reads the lines written since the last call and passes on the completed entries.
+    /// Returns whether anything was read.
+    fn poll(&mut self, on_entry: &mut impl FnMut(Entry) -> io::Result<()>) -> io::Result<bool> {
+        // The file was truncated, e.g. by logrotate's copytruncate
+        let position = self.file.stream_position()?;
+        if self.file.get_ref().metadata()?.len() < position {
+            self.file.seek(SeekFrom::Start(0))?;
+            self.partial.clear();
+        }
+
+        let mut read = false;
+        while self.file.read_line(&mut self.partial)? > 0 {
+            read = true;
+            let Some(line) = self.partial.strip_suffix('\n') else { break };
+            if let Some(entry) = self.parser.push(line.strip_suffix('\r').unwrap_or(line)) {
+                on_entry(entry)?;
+            }
+            self.partial.clear();
+        }
+
+        // Records are written in one piece, so the last entry is complete once nothing more is coming
+        if !read && self.partial.is_empty() {
+            if let Some(entry) = self.parser.finish() {
+                on_entry(entry)?;
+            }
+        }
+        Ok(read)
+    }
+
+    /// Reads the rest of the file and passes on all its remaining entries.
+    fn finish(&mut self, on_entry: &mut impl FnMut(Entry) -> io::Result<()>) -> io::Result<()> {
+        while self.poll(on_entry)? {}
+        // The file was rotated before its last line was terminated
+        if !self.partial.is_empty() {
+            let line = std::mem::take(&mut self.partial);
+            if let Some(entry) = self.parser.push(line.strip_suffix('\r').unwrap_or(&line)) {
+                on_entry(entry)?;
+            }
+        }
+        if let Some(entry) = self.parser.finish() {
+            on_entry(entry)?;
+        }
+        Ok(())
+    }
+}
+
+struct Printer<'a> {
+    matcher: QueryMatcher<'a>,
+    regex: Option<&'a Regex>,
+    out: io::StdoutLock<'static>,
+}
+
+impl<'a> Printer<'a> {
+    fn new(filters: &'a Filters) -> Printer<'a> {
+        match filters.color {
+            Color::Auto => colored::control::set_override(io::stdout().is_terminal()),
+            Color::Always => colored::control::set_override(true),
+            Color::Never => colored::control::set_override(false),
+        }
+
+        Printer { matcher: filters.query.matcher(), regex: filters.regex.as_ref(), out: io::stdout().lock() }
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        self.matcher.matches(entry) && self.regex.is_none_or(|regex| regex.is_match(&entry.message))
+    }
+
+    fn print(&mut self, line: &str, level: Level) -> io::Result<()> {
+        write!(self.out, "{}", logpeek::colorize(line, level))?;
+        self.out.flush()
+    }
+}
+
+fn tail(args: TailArgs) -> io::Result<()> {
+    let dir = args.path.is_dir().then(|| args.path.clone());
+    let mut path = match &dir {
+        Some(dir) => newest_file(dir)?.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no log files named by date in {:?}", dir)))?,
+        None => args.path.clone(),
+    };
+
+    let reader = args.file_options.reader();
+    let format = args.file_options.format;
+    let mut printer = Printer::new(&args.filters);
+    let mut follower = Follower::open(&path, &reader)?;
+
+    let mut last = VecDeque::new();
+    let mut keep_last = |entry: Entry| {
+        if args.lines > 0 && printer.matches(&entry) {
+            if last.len() == args.lines {
+                last.pop_front();
+            }
+            last.push_back(entry);
+        }
+        Ok(())
+    };
+    if args.follow {
+        while follower.poll(&mut keep_last)? {}
+    } else {
+        follower.finish(&mut keep_last)?;
+    }
+    for entry in last {
+        printer.print(&render(&entry, format, false), entry.level)?;
+    }
+
+    if !args.follow {
+        return Ok(());
+    }
+
+    let mut print = |entry: Entry| if printer.matches(&entry) {
+        printer.print(&render(&entry, format, false), entry.level)
+    } else {
+        Ok(())
+    };
+    loop {
+        if follower.poll(&mut print)? {
+            continue;
+        }
+
+        // `SplitLogFiles` created a new file, the old one won't get any more entries
+        if let Some(newest) = rotated(dir.as_deref(), &path)? {
+            follower.finish(&mut print)?;
+            follower = Follower::open(&newest, &reader)?;
+            path = newest;
+            continue;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn merge(args: MergeArgs) -> io::Result<()> {
+    let readers = args.sources.iter().map(|(_, options)| options.reader()).collect::<Vec<_>>();
+    let inputs = args.sources.iter().zip(&readers)
+        .map(|((path, _), reader)| reader.read_path(path).map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err))))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut printer = Printer::new(&args.filters);
+
+    for merged in logpeek::merge::merge(inputs) {
+        let merged = merged?;
+        if printer.matches(&merged.entry) {
+            let (path, options) = &args.sources[merged.source];
+            let line = format!("[{}] {}", path.display(), render(&merged.entry, options.format, true));
+            printer.print(&line, merged.entry.level)?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let result = match args.next().as_deref() {
+        Some("tail") => match TailArgs::parse(args) {
+            Ok(Some(args)) => tail(args),
+            Ok(None) => {
+                print!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            },
+            Err(err) => {
+                eprintln!("logpeek: {}\n\n{}", err, USAGE);
+                return ExitCode::from(2);
+            },
+        },
+        Some("merge") => match MergeArgs::parse(args) {
+            Ok(Some(args)) => merge(args),
+            Ok(None) => {
+                print!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            },
+            Err(err) => {
+                eprintln!("logpeek: {}\n\n{}", err, USAGE);
+                return ExitCode::from(2);
+            },
+        },
+        Some("help" | "-h" | "--help") => {
+            print!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        },
+        _ => {
+            eprint!("{}", USAGE);
+            return ExitCode::from(2);
+        },
+    };
+
+    match result {
+        // e.g. piped into `head`
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("logpeek: {}", err);
+            ExitCode::FAILURE
+        },
+        Ok(()) => ExitCode::SUCCESS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+
+    // Cleans up the test directory after the test is done.
+    struct DirCleaner {
+        dir: &'static str,
+    }
+
+    impl Drop for DirCleaner {
+        fn drop(&mut self) {
+            fs::remove_dir_all(self.dir).unwrap();
+        }
+    }
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().into_iter()
+    }
+
+    fn append(path: &Path, contents: &str) {
+        fs::OpenOptions::new().append(true).create(true).open(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    // Verifies that tail options are parsed and invalid arguments are rejected.
+    #[test]
+    fn test_parse_tail_args() {
+        let parsed = TailArgs::parse(args(&["-f", "-n", "5", "--level", "warn", "-t", "app", "--format", "logfmt", "--offset", "+02:00", "logs"])).unwrap().unwrap();
+        assert_eq!(parsed.path, PathBuf::from("logs"));
+        assert!(parsed.follow);
+        assert_eq!(parsed.lines, 5);
+        assert_eq!(parsed.filters.query.min_level, LevelFilter::Warn);
+        assert_eq!(parsed.filters.query.targets, ["app"]);
+        assert!(parsed.file_options.format == OutputFormat::Logfmt);
+        assert_eq!(parsed.file_options.offset, UtcOffset::from_hms(2, 0, 0).unwrap());
+
+        assert_eq!(TailArgs::parse(args(&["--bogus", "logs"])).err().unwrap(), "unknown option --bogus");
+        assert_eq!(TailArgs::parse(args(&["a.log", "b.log"])).err().unwrap(), "only one path can be given");
+        assert_eq!(TailArgs::parse(args(&["logs", "-n"])).err().unwrap(), "-n needs a value");
+        assert!(TailArgs::parse(args(&["--offset", "2h", "logs"])).is_err());
+        assert!(TailArgs::parse(args(&[])).is_err());
+
+        // Help is an option, not a value
+        assert!(TailArgs::parse(args(&["logs", "--help"])).unwrap().is_none());
+        assert!(TailArgs::parse(args(&["-h"])).unwrap().is_none());
+        assert_eq!(TailArgs::parse(args(&["-t", "--help", "logs"])).unwrap().unwrap().filters.query.targets, ["--help"]);
+        assert!(TailArgs::parse(args(&["-e", "-h", "logs"])).unwrap().unwrap().filters.regex.is_some());
+    }
+
+    // Verifies that file options apply to the paths after them when merging.
+    #[test]
+    fn test_parse_merge_args() {
+        let parsed = MergeArgs::parse(args(&["api.log", "--format", "gelf", "--continuation", "| ", "worker.log", "--color", "never"])).unwrap().unwrap();
+
+        let sources = parsed.sources.iter().map(|(path, options)| (path.to_str().unwrap(), options.format, options.continuation.as_deref())).collect::<Vec<_>>();
+        assert!(sources == [("api.log", OutputFormat::PlainText, None), ("worker.log", OutputFormat::Gelf, Some("| "))]);
+        assert!(matches!(parsed.filters.color, Color::Never));
+        assert!(MergeArgs::parse(args(&["--format", "gelf"])).is_err());
+        assert!(MergeArgs::parse(args(&["-h"])).unwrap().is_none());
+        assert_eq!(MergeArgs::parse(args(&["--continuation", "-h", "app.log"])).unwrap().unwrap().sources[0].1.continuation.as_deref(), Some("-h"));
+    }
+
+    // Verifies that entries are laid out like the default template, with GELF timestamps and `utc` shown in UTC.
+    #[test]
+    fn test_render() {
+        let reader = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::RFC3339);
+        let entry = reader.parse_line("2024-03-01T12:00:00+02:00 WARN app src/main.rs:7 [main] - first").unwrap();
+        let mut entry = Entry { message: String::from("first\nsecond"), ..entry };
+        entry.key_values.push((String::from("user"), String::from("bob")));
+
+        assert_eq!(render(&entry, OutputFormat::PlainText, false), "2024-03-01T12:00:00+02:00 WARN app src/main.rs:7 [main] - first\n    second user=bob\n");
+        assert!(render(&entry, OutputFormat::PlainText, true).starts_with("2024-03-01T10:00:00Z WARN app"));
+
+        let gelf = LogReader::new(OutputFormat::Gelf, &DateTimeFormat::ISO8601)
+            .parse_line("{\"short_message\":\"hi\",\"timestamp\":1709287200.000,\"level\":6,\"_target\":\"app\"}")
+            .unwrap();
+        assert_eq!(render(&gelf, OutputFormat::Gelf, false), "2024-03-01T10:00:00Z INFO app - hi\n");
+    }
+
+    // Verifies that only files named by date are considered when looking for the newest file and rotations.
+    #[test]
+    fn test_newest_file() {
+        let dir = "test_bin_newest_file";
+        let _dir_cleaner = DirCleaner { dir };
+        fs::create_dir_all(dir).unwrap();
+        let dir = Path::new(dir);
+        let first = dir.join("2024_03_01_10_00_00.log");
+
+        assert_eq!(newest_file(dir).unwrap(), None);
+        for name in ["2024_03_01_10_00_00.log", "errors.log", "notes.txt"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        assert_eq!(newest_file(dir).unwrap(), Some(first.clone()));
+        assert_eq!(rotated(Some(dir), &first).unwrap(), None);
+
+        let second = dir.join("2024_03_02_10_00_00.log");
+        fs::write(&second, "").unwrap();
+        assert_eq!(newest_file(dir).unwrap(), Some(second.clone()));
+        assert_eq!(rotated(Some(dir), &first).unwrap(), Some(second));
+        assert_eq!(rotated(None, &first).unwrap(), None);
+    }
+
+    // Verifies that a followed file yields entries once they are complete and starts over when it is truncated.
+    #[test]
+    fn test_follower() {
+        let dir = "test_bin_follower";
+        let _dir_cleaner = DirCleaner { dir };
+        fs::create_dir_all(dir).unwrap();
+        let path = Path::new(dir).join("app.log");
+        fs::write(&path, "").unwrap();
+
+        let reader = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::Custom("[hour]:[minute]:[second]"));
+        let mut follower = Follower::open(&path, &reader).unwrap();
+        let mut messages = Vec::new();
+        let mut poll = |follower: &mut Follower| {
+            while follower.poll(&mut |entry| {
+                messages.push(entry.message);
+                Ok(())
+            }).unwrap() {}
+            std::mem::take(&mut messages)
+        };
+
+        // A line that is still being written
+        append(&path, "10:00:00 INFO app - fir");
+        assert!(poll(&mut follower).is_empty());
+        append(&path, "st\n    continued\r\n10:00:01 INFO app - second\n");
+        // The last entry is passed on once nothing more was written
+        assert_eq!(poll(&mut follower), ["first\ncontinued", "second"]);
+        assert!(poll(&mut follower).is_empty());
+
+        // logrotate's copytruncate
+        fs::write(&path, "").unwrap();
+        append(&path, "10:00:02 INFO app - after\n");
+        assert_eq!(poll(&mut follower), ["after"]);
+
+        // The file is rotated before its last line is terminated
+        append(&path, "10:00:03 INFO app - last\r");
+        assert!(poll(&mut follower).is_empty());
+        follower.finish(&mut |entry| {
+            messages.push(entry.message);
+            Ok(())
+        }).unwrap();
+        assert_eq!(messages, ["last"]);
+    }
+}
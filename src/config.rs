@@ -1,6 +1,8 @@
 //! Module containing the `Config` struct and its associated enums.
 use log::LevelFilter;
 
+use crate::redact::Redactor;
+
 
 /// Where the logs are written to.
 /// Defaults to `Console`
@@ -64,6 +66,113 @@ pub enum DateTimeFormat {
     Custom(&'static str),
 }
 
+/// The layout of a single log line.
+/// Defaults to `Default`, which is `{time} {level} {target} - {message}`.
+pub enum LineTemplate {
+    Default,
+    /// A custom layout such as `{time} [{level:<5}] {thread} {module}:{line} {message}`.
+    ///
+    /// Available fields are `time`, `level`, `target`, `message`, `file`, `line`, `location` (`file:line`, empty for
+    /// records without a file), `module`, `thread` (name), `thread_id`, `pid`, `hostname`, `kv` (all key-values)
+    /// and `kv.<key>` (a single key-value).
+    /// Fields can be padded and truncated with the `std::fmt` syntax, e.g. `{target:>20.20}`.
+    /// Use `{{` and `}}` for literal braces.
+    Custom(&'static str),
+}
+
+/// Whether to include the source location (`file:line`) of the log statement in the log entries.
+/// Applies to every output format, with `LineTemplate::Custom` the `{file}` field uses the chosen path style.
+/// GELF messages always carry the `_file` and `_line` fields.
+/// Defaults to `Hidden`.
+#[derive(PartialEq)]
+pub enum SourceLocation {
+    Hidden,
+    /// The file path as reported by the compiler.
+    FullPath,
+    /// Absolute paths (e.g. of dependencies in the cargo registry) are shortened to start at the crate root,
+    /// e.g. `serde-1.0.193/src/de/mod.rs`. Paths that are already relative are kept as they are.
+    RelativePath,
+}
+
+/// Whether to include the module path of the log statement in the log entries.
+/// Applies to every output format.
+/// Defaults to `False`.
+#[derive(PartialEq)]
+pub enum ShowModulePath {
+    True,
+    False,
+}
+
+/// Which information about the thread that emitted a log entry to include.
+/// The thread is determined at the call site, so it stays correct when the entry is written by another thread.
+/// Applies to every output format.
+/// Defaults to `Hidden`.
+#[derive(PartialEq)]
+pub enum ThreadInfo {
+    Hidden,
+    /// The thread name, `<unnamed>` for threads without one.
+    Name,
+    /// The numeric thread id assigned by the standard library.
+    Id,
+    NameAndId,
+}
+
+/// Whether to include the id of the current process in the log entries.
+/// Applies to every output format.
+/// Defaults to `False`.
+#[derive(PartialEq)]
+pub enum ShowProcessId {
+    True,
+    False,
+}
+
+/// The format in which log entries are written.
+/// Can be chosen separately for the file and the console output.
+/// Defaults to `PlainText`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Lines laid out according to `LineTemplate`.
+    PlainText,
+    /// `ts=... level=info target=... msg="..."` followed by the record key-values, as preferred by Loki/Grafana.
+    /// Key-values named like one of these fields get an underscore in front, e.g. `_msg`.
+    Logfmt,
+    /// One GELF 1.1 JSON object per line, as understood by Graylog.
+    Gelf,
+}
+
+/// How control characters (CR, LF, ANSI escape sequences, ...) in messages, key-values, targets, file names, module paths
+/// and thread names are written in the plain text format.
+/// Without escaping, a user controlled string containing a line break can forge additional log entries.
+/// Logfmt and GELF always escape their values.
+/// Defaults to `Escape` for files and `None` for the console.
+#[derive(PartialEq)]
+pub enum Escaping {
+    /// Everything is written verbatim.
+    None,
+    /// Control characters are written as `\n`, `\r`, `\t` or `\u{1b}`, so every entry stays on a single line.
+    /// Backslashes are written as `\\`, so an escaped control character can't be confused with the same text in the value.
+    Escape,
+    /// Lines after the first are indented with four spaces, so they can't be mistaken for a new entry.
+    /// Other control characters are escaped.
+    Indent,
+    /// Like `Indent`, but lines after the first start with this marker instead, e.g. `"  | "`. Pick a marker that
+    /// no entry starts with, and tell `LogReader::escaping` about it to read the files back.
+    /// `Logger::new` panics if it's empty or contains a line break.
+    Marker(&'static str),
+    /// Line breaks and tabs are replaced with a space, ANSI escape sequences and other control characters are removed.
+    Strip,
+}
+
+/// Whether to additionally send every log entry written to the default destinations to Graylog as a GELF message.
+/// Records that only a routing rule accepts, including `RouteMode::Only` records, are not sent.
+/// Defaults to `Disabled`.
+pub enum GelfOutput {
+    Disabled,
+    /// Messages are sent via UDP to `address` (e.g. `graylog.local:12201`).
+    /// Messages larger than `max_chunk_size` bytes are split into GELF chunks, 8192 is a safe choice for most networks.
+    Udp { address: String, max_chunk_size: usize },
+}
+
 /// Whether to use ANSI escape codes to color the log entries in the terminal.
 /// Defaults to `True`.
 #[derive(PartialEq)]
@@ -91,8 +200,267 @@ pub enum SplitLogFiles {
     False
 }
 
+/// Whether a sidecar index is written next to a log file when `SplitLogFiles` rotates it, as `<file name>.idx`.
+/// The index holds the byte offset and time of a record roughly every `n` bytes, so `LogReader::query` can skip
+/// to the start of a time range instead of reading the whole file. The file that is currently written has no index.
+/// This has no effect on encrypted log files.
+/// Defaults to `Disabled`.
+#[derive(PartialEq)]
+pub enum LogIndex {
+    Disabled,
+    Every(u64),
+}
+
+/// When log files are synced to disk with `File::sync_data`. Without syncing, records written in `Synchronous`
+/// mode survive a crash of the process, but may be lost on a power loss or kernel panic.
+///
+/// Each sync blocks the logging thread until the disk confirms the write, which usually takes from a fraction
+/// of a millisecond on SSDs to tens of milliseconds on spinning disks or network storage. `EveryRecords(1)`
+/// limits logging to that rate, so prefer one of the batched modes for anything but low volume audit logs.
+/// Defaults to `Never`.
+#[derive(PartialEq)]
+pub enum Durability {
+    /// Syncing is left to the operating system.
+    Never,
+    /// The file is synced after every `n` records.
+    EveryRecords(u32),
+    /// The file is synced when a record is written at least this many milliseconds after the last sync.
+    /// There's no timer: records written before a quiet period stay unsynced until the next record is written,
+    /// `Logger::flush` is called or the operating system writes them out.
+    OnWriteAfterMillis(u64),
+    /// The file is synced after every `Error` record, so the records leading up to an error are kept.
+    OnError,
+}
+
+/// What happens to records while a log file can't be written, e.g. because the disk is full.
+/// The logger keeps trying to reopen the file, waiting twice as long after each failed attempt (up to 30 seconds).
+/// Once the file can be written again, a notice with the number of records that are missing from it is written first.
+/// With `LoggingStrategy::Asynchronous`, the records that were still buffered when writing failed count as missing.
+/// Defaults to `Stderr`.
+pub enum WriteFailurePolicy {
+    /// Records are written to stderr instead.
+    Stderr,
+    /// Up to this many records are kept in memory and written to the file once it works again.
+    /// When the buffer is full, the oldest records are dropped.
+    Memory(usize),
+    /// Records are dropped.
+    Drop,
+}
+
+/// Whether the logger periodically logs its own counters (see `logpeek::stats()`) as an `Info` record with the
+/// target `logpeek::stats`. The interval is checked whenever a record is logged, so nothing is emitted while the
+/// application is idle.
+/// Defaults to `Disabled`.
+pub enum EmitStats {
+    Disabled,
+    Every(std::time::Duration),
+}
+
+/// Keeps counts of the records written in a sliding window, per level and per target pattern, so that e.g. a health
+/// endpoint can report the errors of the last 5 minutes. See `logpeek::stats::recent`.
+/// Defaults to `Disabled`.
+pub enum RecentCounts {
+    Disabled,
+    /// Counts are kept in one second buckets for the last `window`. Each of the `targets` (target patterns as in
+    /// `Config::target_filter`) gets its own counts, queried with `logpeek::stats::recent_for_target`.
+    Window { window: std::time::Duration, targets: Vec<&'static str> },
+}
+
+/// Unix permissions for the log directories and files created by the logger. Has no effect on other platforms.
+/// Defaults to `Inherit`.
+pub enum FilePermissions {
+    /// Directories and files are created with the process defaults and existing files aren't checked.
+    Inherit,
+    /// Directories are created with `dir_mode` and files with `file_mode` (e.g. `0o750` and `0o640`),
+    /// both still restricted by the process umask. `existing_files` decides what happens when an existing
+    /// log file grants permissions that `file_mode` doesn't.
+    Restrict { dir_mode: u32, file_mode: u32, existing_files: LooserPermissions },
+}
+
+/// What to do when an existing log file has looser permissions than `FilePermissions::Restrict` allows.
+#[derive(PartialEq)]
+pub enum LooserPermissions {
+    /// The file is used as it is.
+    Ignore,
+    /// A warning is printed to stderr and the file is used as it is.
+    Warn,
+    /// The logger refuses to write to the file and panics.
+    Refuse,
+}
+
+/// Limits how many records are logged, to keep a hot loop from filling the disk.
+/// Records over the limit are dropped. Once a record with the same key is allowed again, or the logger is flushed,
+/// a summary line with the number of dropped records is written.
+/// Defaults to `Disabled`.
+pub enum RateLimit {
+    Disabled,
+    /// One token bucket per call site (`file:line`). Each bucket holds up to `burst` records
+    /// and is refilled with `per_second` records per second, which must be greater than 0.
+    PerCallsite { burst: u32, per_second: f64 },
+    /// Like `PerCallsite`, but with one bucket per target and formatted message.
+    PerMessage { burst: u32, per_second: f64 },
+}
+
+/// Whether consecutive identical records (same level, target and message) are collapsed into one.
+/// When a different record arrives or the logger is flushed, a `Previous message repeated N times` line is written,
+/// with the time of the last duplicate.
+/// Defaults to `False`.
+#[derive(PartialEq)]
+pub enum SuppressDuplicates {
+    True,
+    False,
+}
+
+/// Keeps only a fraction of the records matching `level` and `target`, e.g. 1% of `Debug` records.
+pub struct SamplingRule {
+    /// If set, the rule only applies to records of exactly this level.
+    pub level: Option<log::Level>,
+    /// If set, the rule only applies to this target (a target pattern as in `Config::target_filter`).
+    pub target: Option<&'static str>,
+    /// The fraction of records that is kept, between `0.0` and `1.0`. `Logger::new` panics if it's NaN.
+    pub rate: f64,
+    /// If set, the decision is derived from the value of this key-value (record or context field),
+    /// so e.g. every record of a request is either kept or dropped. Records without the key are sampled randomly.
+    pub key: Option<&'static str>,
+}
+
+/// Where a `Route` sends its records.
+pub enum RouteDestination {
+    /// The console, as configured by `console_mode` and `use_term_color`.
+    Console,
+    /// The log file of `LoggingMode::File` or `LoggingMode::FileAndConsole`.
+    MainFile,
+    /// An additional log file with the given name, placed in the `out_dir_name` directory. It is never split.
+    File(String),
+}
+
+/// Whether the records matched by a `Route` still go to the default destinations selected by `LoggingMode`.
+#[derive(PartialEq)]
+pub enum RouteMode {
+    /// The record is written to the route destination in addition to the default destinations.
+    Also,
+    /// The record is written to the route destination instead of the default destinations.
+    Only,
+}
+
+/// Sends records at or above `min_level` from the given targets to an additional destination.
+///
+/// For example, `Error` records can also go to `errors.log`, `audit` targets only to `audit.log`,
+/// and noisy targets only to the main file and never to the console:
+/// ```
+/// use log::LevelFilter;
+/// use logpeek::config::{Config, LoggingMode, OutputFormat, Route, RouteDestination, RouteMode};
+///
+/// let config = Config {
+///     logging_mode: LoggingMode::FileAndConsole,
+///     routes: vec![
+///         Route { destination: RouteDestination::File("errors.log".to_string()), targets: vec![], min_level: LevelFilter::Error, format: OutputFormat::PlainText, mode: RouteMode::Also },
+///         Route { destination: RouteDestination::File("audit.log".to_string()), targets: vec!["audit"], min_level: LevelFilter::Trace, format: OutputFormat::Logfmt, mode: RouteMode::Only },
+///         Route { destination: RouteDestination::MainFile, targets: vec!["hyper", "h2"], min_level: LevelFilter::Info, format: OutputFormat::PlainText, mode: RouteMode::Only },
+///     ],
+///     ..Default::default()
+/// };
+/// ```
+///
+/// Each route has its own minimum level, which may be less severe than `min_log_level`. `min_log_level` only applies
+/// to the default destinations. A route to a destination that already receives the record is ignored.
+pub struct Route {
+    pub destination: RouteDestination,
+    /// Target patterns as in `Config::target_filter`. An empty list matches every target.
+    pub targets: Vec<&'static str>,
+    pub min_level: LevelFilter,
+    pub format: OutputFormat,
+    pub mode: RouteMode,
+}
+
+/// Secrets to remove from every record before it reaches any output.
+/// Redaction applies to the message and the key-values, including context fields.
+/// Defaults to no redaction, with `[REDACTED]` as the mask.
+pub struct Redaction {
+    /// Key-values whose value is replaced with `mask`, compared case-insensitively (e.g. `password`, `authorization`).
+    pub keys: Vec<&'static str>,
+    /// Regular expressions whose matches are replaced with `mask`, see `redact::BEARER_TOKEN_PATTERN`
+    /// and `redact::CARD_NUMBER_PATTERN`. Requires the `regex` feature.
+    #[cfg(feature = "regex")]
+    pub patterns: Vec<&'static str>,
+    /// Custom redaction steps, applied after the patterns.
+    pub redactors: Vec<Box<dyn Redactor>>,
+    pub mask: &'static str,
+}
+
+impl Default for Redaction {
+    fn default() -> Self {
+        Redaction {
+            keys: Vec::new(),
+            #[cfg(feature = "regex")]
+            patterns: Vec::new(),
+            redactors: Vec::new(),
+            mask: "[REDACTED]",
+        }
+    }
+}
+
+/// What to do with a record whose message matches a `MessageFilter`.
+#[cfg(feature = "regex")]
+#[derive(Clone, Copy, PartialEq)]
+pub enum MessageFilterAction {
+    /// The record is not logged.
+    Drop,
+    /// The record is logged with the given level instead, and dropped if no destination accepts that level.
+    /// Records that are already less severe keep their level.
+    Downgrade(log::Level),
+}
+
+/// Drops or downgrades records whose formatted message matches `pattern`.
+/// Requires the `regex` feature.
+#[cfg(feature = "regex")]
+pub struct MessageFilter {
+    /// A regular expression in the syntax of the `regex` crate, searched for anywhere in the message.
+    pub pattern: &'static str,
+    /// If set, the filter only applies to this target (a target pattern as in `Config::target_filter`).
+    pub target: Option<&'static str>,
+    pub action: MessageFilterAction,
+}
+
+/// Whether every record written to a log file carries a running hash, so that edits can be detected
+/// with `hash_chain::Verifier`. Applies to the main log file and the files of routing rules.
+/// Appending to an existing file continues the chain from its last hash. Turning the chain on for an existing file
+/// that was written without it makes that file fail verification, since its earlier records have no hashes, so start
+/// a new file when turning it on.
+/// Requires the `hash-chain` feature. Defaults to `Disabled`.
+#[cfg(feature = "hash-chain")]
+#[derive(PartialEq)]
+pub enum HashChain {
+    Disabled,
+    /// Each hash is an HMAC-SHA256 of the previous hash and the record, keyed with `key`. Plain text lines end with
+    /// ` #sha256=<hash>`, logfmt lines get a `sha256` field and GELF messages a `_sha256` field.
+    ///
+    /// Anyone with the key can rewrite a file with a valid chain, and records removed from the end of the last file
+    /// aren't detected, see the `hash_chain` module.
+    HmacSha256 { key: Vec<u8> },
+}
+
+/// Whether log files are encrypted at rest, see the `encryption` module for the file format and `DecryptingReader`.
+/// Applies to the main log file and the files of routing rules.
+/// Requires the `encryption` feature. Defaults to `Disabled`.
+#[cfg(feature = "encryption")]
+pub enum Encryption {
+    Disabled,
+    /// Records are encrypted with ChaCha20-Poly1305 using `key`. `key_id` (at most 255 bytes) is stored unencrypted
+    /// in the file, so that the right key can be found when reading it. To rotate keys, change both: the logger
+    /// continues with the new key when it opens the next file, even if it appends to an existing one.
+    ///
+    /// With `HashChain::HmacSha256`, appending to an existing encrypted file starts a new hash chain.
+    ChaCha20Poly1305 { key_id: String, key: [u8; 32] },
+}
+
 /// 'Config' struct that contains the configuration options for the logger.
 /// Use `Default::default()` for the default settings.
+///
+/// `target_filter` and `target_allow` take target patterns. A plain pattern such as `hyper` matches the target and
+/// every module below it (`hyper::proto::h1`, but not `hyperlocal`). Patterns containing `*` or `?` are globs
+/// matched against the whole target, e.g. `*::db::*`. A record is logged if its target matches `target_allow`
+/// (when set) and doesn't match `target_filter`.
 pub struct Config {
     pub out_file_name: OutputFileName,
     pub out_dir_name: OutputDirName,
@@ -101,10 +469,43 @@ pub struct Config {
     pub logging_mode: LoggingMode,
     pub console_mode: ConsoleMode,
     pub datetime_format: DateTimeFormat,
+    pub line_template: LineTemplate,
+    pub source_location: SourceLocation,
+    pub show_module_path: ShowModulePath,
+    pub thread_info: ThreadInfo,
+    pub show_process_id: ShowProcessId,
+    pub file_format: OutputFormat,
+    pub console_format: OutputFormat,
+    pub file_escaping: Escaping,
+    pub console_escaping: Escaping,
+    pub gelf_output: GelfOutput,
+    pub routes: Vec<Route>,
     pub use_term_color: UseTermColor,
     pub logging_strategy: LoggingStrategy,
     pub split_log_files: SplitLogFiles,
+    pub log_index: LogIndex,
+    pub file_permissions: FilePermissions,
+    /// Also applies to the files of routing rules. `Logger::flush` always syncs unless this is `Never`.
+    pub durability: Durability,
+    pub write_failure: WriteFailurePolicy,
+    pub emit_stats: EmitStats,
+    pub recent_counts: RecentCounts,
+    pub rate_limit: RateLimit,
+    pub suppress_duplicates: SuppressDuplicates,
+    /// Evaluated in order, the first matching rule applies. Records matching no rule are always kept.
+    pub sampling: Vec<SamplingRule>,
+    pub redaction: Redaction,
+    /// Targets that are never logged.
     pub target_filter: Option<Vec<&'static str>>,
+    /// If set, only these targets are logged.
+    pub target_allow: Option<Vec<&'static str>>,
+    /// Evaluated in order, the first matching filter applies.
+    #[cfg(feature = "regex")]
+    pub message_filters: Vec<MessageFilter>,
+    #[cfg(feature = "hash-chain")]
+    pub hash_chain: HashChain,
+    #[cfg(feature = "encryption")]
+    pub encryption: Encryption,
 }
 
 impl Default for Config {
@@ -117,10 +518,38 @@ impl Default for Config {
             logging_mode: LoggingMode::Console,
             console_mode: ConsoleMode::Stdout,
             datetime_format: DateTimeFormat::ISO8601,
+            line_template: LineTemplate::Default,
+            source_location: SourceLocation::Hidden,
+            show_module_path: ShowModulePath::False,
+            thread_info: ThreadInfo::Hidden,
+            show_process_id: ShowProcessId::False,
+            file_format: OutputFormat::PlainText,
+            console_format: OutputFormat::PlainText,
+            file_escaping: Escaping::Escape,
+            console_escaping: Escaping::None,
+            gelf_output: GelfOutput::Disabled,
+            routes: Vec::new(),
             use_term_color: UseTermColor::True,
             logging_strategy: LoggingStrategy::Synchronous,
             split_log_files: SplitLogFiles::False,
+            log_index: LogIndex::Disabled,
+            file_permissions: FilePermissions::Inherit,
+            durability: Durability::Never,
+            write_failure: WriteFailurePolicy::Stderr,
+            emit_stats: EmitStats::Disabled,
+            recent_counts: RecentCounts::Disabled,
+            rate_limit: RateLimit::Disabled,
+            suppress_duplicates: SuppressDuplicates::False,
+            sampling: Vec::new(),
+            redaction: Redaction::default(),
             target_filter: None,
+            target_allow: None,
+            #[cfg(feature = "regex")]
+            message_filters: Vec::new(),
+            #[cfg(feature = "hash-chain")]
+            hash_chain: HashChain::Disabled,
+            #[cfg(feature = "encryption")]
+            encryption: Encryption::Disabled,
         }
     }
 }
\ No newline at end of file
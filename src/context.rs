@@ -0,0 +1,196 @@
+//! Mapped diagnostic context: fields that are attached to every log entry emitted within a scope.
+//!
+//! The fields are kept per thread and are added to the record key-values, so they show up in every output format.
+//! Fields set on the record itself take precedence over context fields with the same key.
+//!
+//! # Examples
+//! ```
+//! use log::info;
+//!
+//! logpeek::context::with([("request_id", "f3a1"), ("tenant", "acme")], || {
+//!     info!("Handling request"); // Carries request_id=f3a1 and tenant=acme
+//! });
+//!
+//! let _guard = logpeek::context::push([("job", "cleanup")]);
+//! info!("Started"); // Carries job=cleanup until `_guard` is dropped
+//! ```
+//!
+//! Async tasks can move between threads at every `.await`, so the thread-local fields have to travel with the task.
+//! Capture them with `current()` and wrap the future with `Context::instrument`, which re-enters the context on every poll:
+//! ```
+//! let context = logpeek::context::current();
+//! let task = context.instrument(async {
+//!     log::info!("Inside the task");
+//! });
+//! # drop(task);
+//! ```
+use std::cell::RefCell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{self, Poll};
+
+thread_local! {
+    static FIELDS: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Removes the fields added by `push` (or `Context::enter`) when dropped.
+/// Guards should be dropped in the reverse order of their creation, as is the case with regular scopes.
+#[must_use = "the context fields are removed as soon as the guard is dropped"]
+pub struct ContextGuard {
+    previous_len: usize,
+    // The guard refers to thread-local state, so it must not be sent to another thread
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        FIELDS.with(|fields| fields.borrow_mut().truncate(self.previous_len));
+    }
+}
+
+/// Adds the fields to the context of the current thread until the returned guard is dropped.
+pub fn push<K, V>(fields: impl IntoIterator<Item = (K, V)>) -> ContextGuard
+where
+    K: Into<String>,
+    V: ToString,
+{
+    FIELDS.with(|current| {
+        let mut current = current.borrow_mut();
+        let previous_len = current.len();
+        current.extend(fields.into_iter().map(|(key, value)| (key.into(), value.to_string())));
+
+        ContextGuard { previous_len, _not_send: PhantomData }
+    })
+}
+
+/// Runs `f` with the fields added to the context of the current thread.
+pub fn with<K, V, R>(fields: impl IntoIterator<Item = (K, V)>, f: impl FnOnce() -> R) -> R
+where
+    K: Into<String>,
+    V: ToString,
+{
+    let _guard = push(fields);
+    f()
+}
+
+/// Captures the fields of the current thread, e.g. to carry them over to another thread or an async task.
+pub fn current() -> Context {
+    Context { fields: FIELDS.with(|fields| fields.borrow().clone()) }
+}
+
+/// Returns the context fields of the current thread with later entries overriding earlier ones with the same key.
+pub(crate) fn fields() -> Vec<(String, String)> {
+    FIELDS.with(|fields| {
+        let fields = fields.borrow();
+        let mut result: Vec<(String, String)> = Vec::with_capacity(fields.len());
+
+        for (key, value) in fields.iter() {
+            match result.iter_mut().find(|(existing, _)| existing == key) {
+                Some(existing) => existing.1.clone_from(value),
+                None => result.push((key.clone(), value.clone())),
+            }
+        }
+        result
+    })
+}
+
+/// Returns the value of a single context field of the current thread.
+pub(crate) fn field(key: &str) -> Option<String> {
+    FIELDS.with(|fields| {
+        fields.borrow().iter().rev().find(|(existing, _)| existing == key).map(|(_, value)| value.clone())
+    })
+}
+
+/// A snapshot of context fields, see `current`.
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    fields: Vec<(String, String)>,
+}
+
+impl Context {
+    /// Adds the captured fields to the context of the current thread until the returned guard is dropped.
+    pub fn enter(&self) -> ContextGuard {
+        push(self.fields.iter().map(|(key, value)| (key.as_str(), value.as_str())))
+    }
+
+    /// Runs `f` with the captured fields added to the context of the current thread.
+    pub fn scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _guard = self.enter();
+        f()
+    }
+
+    /// Wraps the future so that the captured fields are in the context whenever it is polled.
+    pub fn instrument<F: Future>(self, future: F) -> WithContext<F> {
+        WithContext { context: self, future }
+    }
+}
+
+/// A future that enters its `Context` on every poll, see `Context::instrument`.
+pub struct WithContext<F> {
+    context: Context,
+    future: F,
+}
+
+impl<F: Future> Future for WithContext<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<F::Output> {
+        // SAFETY: `future` is structurally pinned, it is never moved out of `self`
+        let this = unsafe { self.get_unchecked_mut() };
+        let _guard = this.context.enter();
+
+        unsafe { Pin::new_unchecked(&mut this.future) }.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+    use std::thread;
+    use super::*;
+
+    // Verifies that nested scopes add and remove their fields.
+    #[test]
+    fn test_nested_scopes() {
+        with([("request_id", "1")], || {
+            let guard = push([("tenant", "acme"), ("request_id", "2")]);
+            assert_eq!(fields(), vec![(String::from("request_id"), String::from("2")), (String::from("tenant"), String::from("acme"))]);
+
+            drop(guard);
+            assert_eq!(fields(), vec![(String::from("request_id"), String::from("1"))]);
+        });
+
+        assert!(fields().is_empty());
+    }
+
+    // Verifies that a captured context can be entered on another thread.
+    #[test]
+    fn test_context_on_other_thread() {
+        let context = with([("request_id", 7)], current);
+
+        let fields = thread::spawn(move || context.scope(fields)).join().unwrap();
+
+        assert_eq!(fields, vec![(String::from("request_id"), String::from("7"))]);
+    }
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    // Verifies that an instrumented future sees the captured fields while being polled, and only then.
+    #[test]
+    fn test_instrumented_future() {
+        let context = with([("task", "sync")], current);
+        let mut future = Box::pin(context.instrument(async { fields() }));
+
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let result = future.as_mut().poll(&mut task::Context::from_waker(&waker));
+
+        assert_eq!(result, Poll::Ready(vec![(String::from("task"), String::from("sync"))]));
+        assert!(fields().is_empty());
+    }
+}
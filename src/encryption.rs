@@ -0,0 +1,503 @@
+//! Encrypted log files, see `config::Encryption`.
+//!
+//! An encrypted log file is a sequence of segments. Each time the logger opens the file, it starts a new segment
+//! with a header holding the id of the key and a random nonce prefix. Every record written after it is encrypted
+//! and authenticated on its own with ChaCha20-Poly1305, using the prefix and a counter as the nonce.
+//! Since the key id is stored with every segment, one file can contain segments written with different keys.
+//!
+//! When the logger closes the file, it ends the segment with an empty final record. A segment without it was cut off:
+//! either the logger didn't close the file (it crashed, is still writing it, or is the global logger of a process
+//! that exited) or records were removed from its end. `DecryptingReader::incomplete_segments` counts these segments.
+//! A record that was only partly written before a crash is skipped up to the next segment header. Removing whole
+//! segments from the end of the file can't be detected from the file alone.
+//!
+//! Use `DecryptingReader` to read the records back:
+//! ```no_run
+//! use std::io::{BufRead, Read};
+//! use logpeek::encryption::DecryptingReader;
+//!
+//! let key = [7; 32]; // Load this from your secret store
+//! let mut reader = DecryptingReader::open("logs/audit.log", [("2024-01", key)]).unwrap();
+//! for line in reader.by_ref().lines() {
+//!     println!("{}", line.unwrap());
+//! }
+//! if reader.incomplete_segments() > 0 {
+//!     eprintln!("{} segments were cut off", reader.incomplete_segments());
+//! }
+//! ```
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+
+pub type Key = [u8; 32];
+
+const HEADER_MAGIC: &[u8; 4] = b"LPEH";
+const CHUNK_MAGIC: &[u8; 4] = b"LPEC";
+const VERSION: u8 = 1;
+
+/// Marks the chunk that ends a segment. The flag is authenticated, so it can't be added or removed.
+const FINAL_CHUNK: u8 = 1;
+const DATA_CHUNK: u8 = 0;
+/// The bytes a chunk adds to its record: 4 magic + 1 flag + 4 length + 16 tag.
+pub(crate) const CHUNK_OVERHEAD: usize = 25;
+
+fn nonce(prefix: &[u8; 8], counter: u32) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..8].copy_from_slice(prefix);
+    nonce[8..].copy_from_slice(&counter.to_be_bytes());
+    nonce.into()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The additional authenticated data of a chunk.
+fn aad(key_id: &str, flag: u8) -> Vec<u8> {
+    [key_id.as_bytes(), &[flag]].concat()
+}
+
+/// Encrypts every write into its own chunk. The segment header is written when the writer is created,
+/// the final chunk when it is dropped.
+pub(crate) struct EncryptingWriter<W: Write> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    key_id: String,
+    nonce_prefix: [u8; 8],
+    counter: u32,
+    finished: bool,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn new(mut inner: W, key_id: &str, key: &Key) -> io::Result<EncryptingWriter<W>> {
+        let key_id_len = u8::try_from(key_id.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Encryption key ids can't be longer than 255 bytes")
+        })?;
+
+        let mut nonce_prefix = [0; 8];
+        getrandom::getrandom(&mut nonce_prefix).map_err(|err| io::Error::other(err.to_string()))?;
+
+        let mut header = Vec::with_capacity(14 + key_id.len());
+        header.extend_from_slice(HEADER_MAGIC);
+        header.push(VERSION);
+        header.push(key_id_len);
+        header.extend_from_slice(key_id.as_bytes());
+        header.extend_from_slice(&nonce_prefix);
+        inner.write_all(&header)?;
+
+        Ok(EncryptingWriter {
+            inner,
+            cipher: ChaCha20Poly1305::new(key.into()),
+            key_id: key_id.to_string(),
+            nonce_prefix,
+            counter: 0,
+            finished: false,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Ends the segment with the final chunk. Nothing can be written after it.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.write_chunk(b"", FINAL_CHUNK)?;
+            self.finished = true;
+        }
+        self.inner.flush()
+    }
+
+    fn write_chunk(&mut self, buf: &[u8], flag: u8) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other("The encrypted segment was already finished"));
+        }
+        // The counter is part of the nonce, so it must never wrap around
+        let next_counter = self.counter.checked_add(1).ok_or_else(|| {
+            io::Error::other("Too many records in one encrypted segment, the file has to be reopened")
+        })?;
+
+        let ciphertext = self.cipher
+            .encrypt(&nonce(&self.nonce_prefix, self.counter), Payload { msg: buf, aad: &aad(&self.key_id, flag) })
+            .map_err(|_| io::Error::other("Failed to encrypt log record"))?;
+        let len = u32::try_from(ciphertext.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Log record too large to encrypt")
+        })?;
+
+        let mut chunk = Vec::with_capacity(9 + ciphertext.len());
+        chunk.extend_from_slice(CHUNK_MAGIC);
+        chunk.push(flag);
+        chunk.extend_from_slice(&len.to_be_bytes());
+        chunk.extend_from_slice(&ciphertext);
+        self.inner.write_all(&chunk)?;
+
+        self.counter = next_counter;
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for EncryptingWriter<W> {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            eprintln!("logpeek: failed to finish the encrypted log file: {}", err);
+        }
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_chunk(buf, DATA_CHUNK)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct Segment {
+    cipher: ChaCha20Poly1305,
+    key_id: String,
+    nonce_prefix: [u8; 8],
+    counter: u32,
+    finished: bool,
+    /// The header as it was read, see `next_chunk`.
+    header: Vec<u8>,
+}
+
+/// Why a header or chunk couldn't be read.
+enum Torn {
+    /// The file ended in the middle of it.
+    Eof,
+    Invalid(io::Error),
+}
+
+impl From<io::Error> for Torn {
+    fn from(err: io::Error) -> Self {
+        Torn::Invalid(err)
+    }
+}
+
+/// Reads the decrypted records of an encrypted log file. Fails with `io::ErrorKind::InvalidData` if a record
+/// was modified, a segment uses a key that wasn't given, or the file isn't an encrypted log file.
+///
+/// A header or record that was cut off, e.g. by a crash while it was written, is skipped up to the next segment header.
+/// It and the segments that don't end with a final record are counted in `incomplete_segments`.
+pub struct DecryptingReader<R: Read> {
+    inner: R,
+    keys: Vec<(String, Key)>,
+    segment: Option<Segment>,
+    buffer: Vec<u8>,
+    position: usize,
+    /// Bytes that were read past a cut off header or record, to be read again.
+    pending: Vec<u8>,
+    incomplete_segments: usize,
+}
+
+impl DecryptingReader<BufReader<File>> {
+    pub fn open<'a>(path: impl AsRef<Path>, keys: impl IntoIterator<Item = (&'a str, Key)>) -> io::Result<Self> {
+        Ok(DecryptingReader::new(BufReader::new(File::open(path)?), keys))
+    }
+}
+
+impl<R: Read> DecryptingReader<R> {
+    /// `keys` maps key ids to keys, it has to contain the keys of all segments in the file.
+    pub fn new<'a>(inner: R, keys: impl IntoIterator<Item = (&'a str, Key)>) -> DecryptingReader<R> {
+        DecryptingReader {
+            inner,
+            keys: keys.into_iter().map(|(key_id, key)| (key_id.to_string(), key)).collect(),
+            segment: None,
+            buffer: Vec::new(),
+            position: 0,
+            pending: Vec::new(),
+            incomplete_segments: 0,
+        }
+    }
+
+    /// The number of segments read so far that were cut off, see the module docs. Only complete once the
+    /// whole file was read. The segment the logger is currently writing is always counted.
+    pub fn incomplete_segments(&self) -> usize {
+        self.incomplete_segments
+    }
+
+    /// Reads the next chunk into the buffer, handling any segment headers before it. Returns `false` at the end of the file.
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        loop {
+            let mut record = Vec::new();
+            let result = if !self.read_up_to(&mut record, HEADER_MAGIC.len())? {
+                if record.is_empty() {
+                    self.end_segment();
+                    return Ok(false);
+                }
+                Err(Torn::Eof)
+            } else if record.starts_with(HEADER_MAGIC) {
+                self.read_header(&mut record).map(|()| false)
+            } else if record.starts_with(CHUNK_MAGIC) {
+                self.read_chunk(&mut record)
+            } else if self.segment.is_none() {
+                return Err(invalid_data(String::from("Not an encrypted log file")));
+            } else {
+                Err(Torn::Invalid(invalid_data(String::from("Expected an encrypted record, the file was modified"))))
+            };
+
+            let torn = match result {
+                Ok(true) => return Ok(true),
+                Ok(false) => continue,
+                Err(torn) => torn,
+            };
+
+            // A header or chunk that was cut off is followed by the header the logger wrote when it reopened the
+            // file, which may start within the bytes that were read for it. Anything else is an error. A header
+            // isn't authenticated before the first chunk of its segment, so until then the next header may also
+            // start within it.
+            self.read_up_to(&mut record, HEADER_MAGIC.len() - 1)?;
+            let torn_header = record.starts_with(HEADER_MAGIC);
+            let mut bytes = match &self.segment {
+                Some(segment) if segment.counter == 0 && !torn_header => segment.header.clone(),
+                _ => Vec::new(),
+            };
+            let record_start = bytes.len();
+            bytes.append(&mut record);
+
+            let next_header = bytes.windows(HEADER_MAGIC.len())
+                .enumerate()
+                .position(|(position, window)| position > 0 && position != record_start && window == HEADER_MAGIC);
+            match (next_header, torn) {
+                (Some(position), _) => {
+                    let mut rest = bytes.split_off(position);
+                    rest.append(&mut self.pending);
+                    self.pending = rest;
+                    self.cut_off(torn_header);
+                },
+                (None, Torn::Eof) => {
+                    self.cut_off(torn_header);
+                    return Ok(false);
+                },
+                (None, Torn::Invalid(err)) => return Err(err),
+            }
+        }
+    }
+
+    /// Appends up to `len` bytes to `record`. Returns `false` if the file ended before.
+    fn read_up_to(&mut self, record: &mut Vec<u8>, len: usize) -> io::Result<bool> {
+        let from_pending = len.min(self.pending.len());
+        record.extend(self.pending.drain(..from_pending));
+        let rest = (len - from_pending) as u64;
+        // Read in steps instead of allocating `len` bytes up front, since a cut off length can be anything
+        let read = (&mut self.inner).take(rest).read_to_end(record)?;
+        Ok(read as u64 == rest)
+    }
+
+    fn read_exact_into(&mut self, record: &mut Vec<u8>, len: usize) -> Result<(), Torn> {
+        match self.read_up_to(record, len)? {
+            true => Ok(()),
+            false => Err(Torn::Eof),
+        }
+    }
+
+    /// Counts the segment of a header or chunk that was cut off.
+    fn cut_off(&mut self, torn_header: bool) {
+        // A cut off chunk belongs to the current segment, which `end_segment` counts, a cut off header starts a new one
+        if torn_header || self.segment.as_ref().is_none_or(|segment| segment.finished) {
+            self.incomplete_segments += 1;
+        }
+        self.end_segment();
+    }
+
+    /// Counts the current segment if it didn't end with the final chunk.
+    fn end_segment(&mut self) {
+        if let Some(segment) = self.segment.take() {
+            if !segment.finished {
+                self.incomplete_segments += 1;
+            }
+        }
+    }
+
+    fn read_header(&mut self, record: &mut Vec<u8>) -> Result<(), Torn> {
+        self.read_exact_into(record, 2)?;
+        let (version, key_id_len) = (record[4], usize::from(record[5]));
+        // Read the whole header first, so that the next one is found if this one was cut off
+        self.read_exact_into(record, key_id_len + 8)?;
+        if version != VERSION {
+            return Err(Torn::Invalid(invalid_data(format!("Unsupported encrypted log file version {}", version))));
+        }
+
+        let key_id = String::from_utf8(record[6..6 + key_id_len].to_vec()).map_err(|_| invalid_data(String::from("Invalid key id")))?;
+        let nonce_prefix: [u8; 8] = record[6 + key_id_len..].try_into().expect("the nonce prefix was read");
+
+        let key = self.keys.iter()
+            .find(|(id, _)| *id == key_id)
+            .map(|(_, key)| key)
+            .ok_or_else(|| invalid_data(format!("No key for key id {:?}", key_id)))?;
+
+        let segment = Segment {
+            cipher: ChaCha20Poly1305::new(key.into()),
+            key_id,
+            nonce_prefix,
+            counter: 0,
+            finished: false,
+            header: record.clone(),
+        };
+        self.end_segment();
+        self.segment = Some(segment);
+        Ok(())
+    }
+
+    /// Reads and decrypts a chunk. Returns `true` for a data chunk and `false` for the final one.
+    fn read_chunk(&mut self, record: &mut Vec<u8>) -> Result<bool, Torn> {
+        self.read_exact_into(record, 5)?;
+        let flag = record[4];
+        let len = u32::from_be_bytes(record[5..9].try_into().expect("the length was read"));
+        self.read_exact_into(record, len as usize)?;
+
+        let segment = self.segment.as_mut().ok_or_else(|| invalid_data(String::from("Record before the first segment header")))?;
+        if segment.finished {
+            return Err(Torn::Invalid(invalid_data(String::from("Record after the end of its segment, the file was modified"))));
+        }
+        let plaintext = segment.cipher
+            .decrypt(&nonce(&segment.nonce_prefix, segment.counter), Payload { msg: &record[9..], aad: &aad(&segment.key_id, flag) })
+            .map_err(|_| invalid_data(String::from("Log record failed authentication, the file was modified")))?;
+        segment.counter = segment.counter.wrapping_add(1);
+
+        if flag == FINAL_CHUNK {
+            segment.finished = true;
+            return Ok(false);
+        }
+        self.buffer = plaintext;
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = buf.len().min(available.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: Read> BufRead for DecryptingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.position >= self.buffer.len() {
+            if !self.next_chunk()? {
+                break;
+            }
+        }
+        Ok(&self.buffer[self.position.min(self.buffer.len())..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position += amount;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(file: &mut Vec<u8>, key_id: &str, key: &Key, records: &[&str]) {
+        let mut writer = EncryptingWriter::new(file, key_id, key).unwrap();
+        for record in records {
+            writer.write_all(record.as_bytes()).unwrap();
+        }
+    }
+
+    fn decrypt(file: &[u8], keys: &[(&str, Key)]) -> io::Result<String> {
+        let mut contents = String::new();
+        DecryptingReader::new(file, keys.iter().copied()).read_to_string(&mut contents)?;
+        Ok(contents)
+    }
+
+    // Verifies that segments written with different keys are decrypted in order.
+    #[test]
+    fn test_round_trip_with_key_rotation() {
+        let mut file = Vec::new();
+        encrypt(&mut file, "old", &[1; 32], &["first\n", "second\n"]);
+        encrypt(&mut file, "new", &[2; 32], &["third\n"]);
+
+        assert!(!file.windows(6).any(|window| window == b"second"));
+        assert_eq!(decrypt(&file, &[("old", [1; 32]), ("new", [2; 32])]).unwrap(), "first\nsecond\nthird\n");
+        assert!(decrypt(&file, &[("old", [1; 32])]).is_err());
+    }
+
+    // Verifies that modified and removed records fail authentication.
+    #[test]
+    fn test_tampering_detected() {
+        let mut file = Vec::new();
+        encrypt(&mut file, "key", &[1; 32], &["first\n", "second\n"]);
+        let keys = [("key", [1; 32])];
+
+        let mut modified = file.clone();
+        modified[HEADER_LEN + CHUNK_OVERHEAD] ^= 1;
+        assert_eq!(decrypt(&modified, &keys).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut removed = file[..HEADER_LEN].to_vec();
+        removed.extend_from_slice(&file[HEADER_LEN + chunk_len("first\n")..]);
+        assert!(decrypt(&removed, &keys).is_err());
+    }
+
+    // Header: 4 magic + 2 + 3 key id + 8 nonce prefix
+    const HEADER_LEN: usize = 17;
+    fn chunk_len(record: &str) -> usize {
+        CHUNK_OVERHEAD + record.len()
+    }
+
+    fn decrypt_counting(file: &[u8], keys: &[(&str, Key)]) -> (String, usize) {
+        let mut reader = DecryptingReader::new(file, keys.iter().copied());
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        (contents, reader.incomplete_segments())
+    }
+
+    // Verifies that records and segments removed from the end of a segment are detected.
+    #[test]
+    fn test_truncation_detected() {
+        let mut file = Vec::new();
+        encrypt(&mut file, "key", &[1; 32], &["first\n", "second\n"]);
+        let keys = [("key", [1; 32])];
+        assert_eq!(decrypt_counting(&file, &keys), (String::from("first\nsecond\n"), 0));
+
+        let without_final = &file[..file.len() - CHUNK_OVERHEAD];
+        assert_eq!(decrypt_counting(without_final, &keys), (String::from("first\nsecond\n"), 1));
+
+        let without_last = &file[..HEADER_LEN + chunk_len("first\n")];
+        assert_eq!(decrypt_counting(without_last, &keys), (String::from("first\n"), 1));
+
+        let partial_magic = &file[..HEADER_LEN + chunk_len("first\n") + 2];
+        assert_eq!(decrypt_counting(partial_magic, &keys), (String::from("first\n"), 1));
+
+        // The flag of the final chunk is authenticated
+        let mut unflagged = file.clone();
+        unflagged[HEADER_LEN + chunk_len("first\n") + chunk_len("second\n") + 4] = DATA_CHUNK;
+        assert!(decrypt(&unflagged, &keys).is_err());
+    }
+
+    // Verifies that a chunk cut off by a crash is skipped up to the segment written after it.
+    #[test]
+    fn test_resync_after_torn_chunk() {
+        let keys = [("key", [1; 32])];
+        for cut in [2, 7, chunk_len("second\n") - 1] {
+            let mut file = Vec::new();
+            encrypt(&mut file, "key", &[1; 32], &["first\n", "second\n"]);
+            file.truncate(HEADER_LEN + chunk_len("first\n") + cut);
+            encrypt(&mut file, "key", &[1; 32], &["third\n"]);
+
+            assert_eq!(decrypt_counting(&file, &keys), (String::from("first\nthird\n"), 1), "cut after {} bytes", cut);
+        }
+
+        // A cut off header
+        let mut file = Vec::new();
+        encrypt(&mut file, "key", &[1; 32], &["first\n"]);
+        let mut torn = Vec::new();
+        encrypt(&mut torn, "key", &[1; 32], &[]);
+        file.extend_from_slice(&torn[..10]);
+        encrypt(&mut file, "key", &[1; 32], &["second\n"]);
+        assert_eq!(decrypt_counting(&file, &keys), (String::from("first\nsecond\n"), 1));
+    }
+}
@@ -0,0 +1,186 @@
+//! Escaping of control characters in the plain text format, see `config::Escaping`.
+use std::borrow::Cow;
+use std::fmt::Write;
+
+use crate::config::Escaping;
+
+/// The indentation used for continuation lines by `Escaping::Indent`.
+const CONTINUATION_INDENT: &str = "    ";
+
+fn needs_handling(c: char) -> bool {
+    c.is_control() || c == '\u{2028}' || c == '\u{2029}'
+}
+
+/// Applies the escaping policy to a message, key-value or other text field. Values without control characters
+/// (or backslashes, for `Escaping::Escape`) are returned as they are.
+pub(crate) fn escape<'a>(value: &'a str, escaping: &Escaping) -> Cow<'a, str> {
+    let needs_handling = |c: char| needs_handling(c) || (c == '\\' && *escaping == Escaping::Escape);
+    if *escaping == Escaping::None || !value.chars().any(needs_handling) {
+        return Cow::Borrowed(value);
+    }
+
+    let mut result = String::with_capacity(value.len() + 8);
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if !needs_handling(c) {
+            result.push(c);
+            continue;
+        }
+
+        match escaping {
+            Escaping::None => result.push(c),
+            Escaping::Escape => push_escaped(&mut result, c),
+            Escaping::Indent | Escaping::Marker(_) => match c {
+                '\n' | '\u{2028}' | '\u{2029}' => {
+                    result.push('\n');
+                    result.push_str(match escaping {
+                        Escaping::Marker(marker) => marker,
+                        _ => CONTINUATION_INDENT,
+                    });
+                },
+                '\r' if chars.peek() == Some(&'\n') => {},
+                c => push_escaped(&mut result, c),
+            },
+            Escaping::Strip => match c {
+                '\n' | '\r' | '\t' | '\u{2028}' | '\u{2029}' if !result.ends_with(' ') => result.push(' '),
+                '\u{1b}' => skip_ansi_sequence(&mut chars),
+                _ => {},
+            },
+        }
+    }
+
+    Cow::Owned(result)
+}
+
+/// Reverses `Escaping::Escape`. Backslashes that don't start one of its escape sequences are kept as they are.
+pub(crate) fn unescape(value: &str) -> Cow<'_, str> {
+    if !value.contains('\\') {
+        return Cow::Borrowed(value);
+    }
+
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        let rest = chars.as_str();
+        let (unescaped, len) = match rest.chars().next() {
+            Some('\\') => ('\\', 1),
+            Some('n') => ('\n', 1),
+            Some('r') => ('\r', 1),
+            Some('t') => ('\t', 1),
+            Some('u') => match rest.strip_prefix("u{").and_then(|hex| hex.split_once('}')) {
+                Some((hex, _)) => match u32::from_str_radix(hex, 16).ok().and_then(char::from_u32) {
+                    Some(c) => (c, hex.len() + 3),
+                    None => ('\\', 0),
+                },
+                None => ('\\', 0),
+            },
+            _ => ('\\', 0),
+        };
+        result.push(unescaped);
+        chars = rest[len..].chars();
+    }
+
+    Cow::Owned(result)
+}
+
+fn push_escaped(result: &mut String, c: char) {
+    match c {
+        '\\' => result.push_str("\\\\"),
+        '\n' => result.push_str("\\n"),
+        '\r' => result.push_str("\\r"),
+        '\t' => result.push_str("\\t"),
+        c => {
+            let _ = write!(result, "\\u{{{:x}}}", c as u32);
+        },
+    }
+}
+
+/// Skips the rest of an ANSI escape sequence after its ESC character: CSI sequences (`ESC [ ... final byte`),
+/// OSC sequences (`ESC ] ... BEL` or `ESC ] ... ESC \`) and two character sequences.
+fn skip_ansi_sequence(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    match chars.next() {
+        Some('[') => {
+            for c in chars.by_ref() {
+                if ('\u{40}'..='\u{7e}').contains(&c) {
+                    break;
+                }
+            }
+        },
+        Some(']') => {
+            while let Some(c) = chars.next() {
+                if c == '\u{7}' {
+                    break;
+                }
+                if c == '\u{1b}' && chars.peek() == Some(&'\\') {
+                    chars.next();
+                    break;
+                }
+            }
+        },
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORGED: &str = "login failed\n2024-01-01T00:00:00Z INFO app - admin logged in\r\n\u{1b}[31mred\u{1b}[0m";
+
+    // Verifies that line breaks and ANSI sequences are escaped.
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(FORGED, &Escaping::Escape), "login failed\\n2024-01-01T00:00:00Z INFO app - admin logged in\\r\\n\\u{1b}[31mred\\u{1b}[0m");
+    }
+
+    // Verifies that backslashes are escaped, so escaped control characters can be told apart from the same text.
+    #[test]
+    fn test_escape_backslash() {
+        assert_eq!(escape("literal \\n and\nbreak", &Escaping::Escape), "literal \\\\n and\\nbreak");
+        assert_eq!(escape("C:\\logs", &Escaping::Escape), "C:\\\\logs");
+        assert!(matches!(escape("C:\\logs", &Escaping::Indent), Cow::Borrowed(_)));
+    }
+
+    // Verifies that unescaping restores the escaped values and keeps unknown sequences.
+    #[test]
+    fn test_unescape() {
+        for value in [FORGED, "literal \\n and\nbreak", "C:\\logs", "bell \u{7} and \u{2028}"] {
+            assert_eq!(unescape(&escape(value, &Escaping::Escape)), value);
+        }
+        assert_eq!(unescape("\\q \\u{zz} \\u{d800} trailing \\"), "\\q \\u{zz} \\u{d800} trailing \\");
+        assert!(matches!(unescape("plain message"), Cow::Borrowed(_)));
+    }
+
+    // Verifies that continuation lines are indented.
+    #[test]
+    fn test_indent() {
+        assert_eq!(escape(FORGED, &Escaping::Indent), "login failed\n    2024-01-01T00:00:00Z INFO app - admin logged in\n    \\u{1b}[31mred\\u{1b}[0m");
+    }
+
+    // Verifies that continuation lines start with the marker, including for Windows line breaks.
+    #[test]
+    fn test_marker() {
+        assert_eq!(escape("failed\r\ncaused by: timeout\n\tat main", &Escaping::Marker("  | ")), "failed\n  | caused by: timeout\n  | \\tat main");
+    }
+
+    // Verifies that line breaks become spaces and ANSI sequences are removed.
+    #[test]
+    fn test_strip() {
+        assert_eq!(escape(FORGED, &Escaping::Strip), "login failed 2024-01-01T00:00:00Z INFO app - admin logged in red");
+        assert_eq!(escape("\u{1b}]0;title\u{7}text", &Escaping::Strip), "text");
+    }
+
+    // Verifies that values without control characters are not copied and that `None` keeps everything.
+    #[test]
+    fn test_unchanged() {
+        assert!(matches!(escape("plain message", &Escaping::Escape), Cow::Borrowed(_)));
+        assert_eq!(escape(FORGED, &Escaping::None), FORGED);
+    }
+}
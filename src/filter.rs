@@ -0,0 +1,142 @@
+//! Target matching for `Config::target_filter` and `Config::target_allow`.
+use std::collections::HashMap;
+
+/// A set of target patterns compiled once in `Logger::new`.
+///
+/// A plain pattern such as `hyper` matches the target itself and every target below it at a module boundary
+/// (`hyper::proto::h1`, but not `hyperlocal`). These are stored in a trie keyed by path segment, so a lookup only
+/// walks the segments of the target. Patterns containing `*` (any run of characters) or `?` (a single character)
+/// are glob patterns that have to match the whole target, e.g. `*::db::*`.
+#[derive(Default)]
+pub(crate) struct TargetMatcher {
+    prefixes: TrieNode,
+    globs: Vec<String>,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    terminal: bool,
+    children: HashMap<String, TrieNode>,
+}
+
+impl TargetMatcher {
+    pub fn new<'a>(patterns: impl IntoIterator<Item = &'a str>) -> TargetMatcher {
+        let mut matcher = TargetMatcher::default();
+
+        for pattern in patterns {
+            if pattern.contains(['*', '?']) {
+                matcher.globs.push(pattern.to_string());
+                continue;
+            }
+
+            let mut node = &mut matcher.prefixes;
+            for segment in pattern.split("::") {
+                node = node.children.entry(segment.to_string()).or_default();
+            }
+            node.terminal = true;
+        }
+
+        matcher
+    }
+
+    pub fn matches(&self, target: &str) -> bool {
+        let mut node = &self.prefixes;
+        for segment in target.split("::") {
+            match node.children.get(segment) {
+                Some(child) if child.terminal => return true,
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+
+        self.globs.iter().any(|glob| glob_matches(glob, target))
+    }
+}
+
+/// Decides whether a target is logged based on the allow and exclude lists.
+pub(crate) struct TargetFilter {
+    allow: Option<TargetMatcher>,
+    exclude: Option<TargetMatcher>,
+}
+
+impl TargetFilter {
+    pub fn new(allow: Option<&[&str]>, exclude: Option<&[&str]>) -> TargetFilter {
+        TargetFilter {
+            allow: allow.map(|patterns| TargetMatcher::new(patterns.iter().copied())),
+            exclude: exclude.map(|patterns| TargetMatcher::new(patterns.iter().copied())),
+        }
+    }
+
+    /// A target is logged if it matches the allow list (when set) and doesn't match the exclude list.
+    pub fn is_enabled(&self, target: &str) -> bool {
+        self.allow.as_ref().is_none_or(|allow| allow.matches(target))
+            && !self.exclude.as_ref().is_some_and(|exclude| exclude.matches(target))
+    }
+}
+
+/// Matches `*` and `?` wildcards against the whole text, backtracking to the last `*` on a mismatch.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verifies that plain patterns match at module boundaries only.
+    #[test]
+    fn test_prefix_matching() {
+        let matcher = TargetMatcher::new(["hyper", "app::db"]);
+
+        assert!(matcher.matches("hyper"));
+        assert!(matcher.matches("hyper::proto::h1"));
+        assert!(matcher.matches("app::db::pool"));
+        assert!(!matcher.matches("hyperlocal"));
+        assert!(!matcher.matches("app"));
+        assert!(!matcher.matches("app::dbx"));
+    }
+
+    // Verifies glob patterns with `*` and `?`.
+    #[test]
+    fn test_glob_matching() {
+        let matcher = TargetMatcher::new(["*::db::*", "h2?"]);
+
+        assert!(matcher.matches("app::db::pool"));
+        assert!(matcher.matches("other::nested::db::x"));
+        assert!(matcher.matches("h2c"));
+        assert!(!matcher.matches("app::db"));
+        assert!(!matcher.matches("h2"));
+    }
+
+    // Verifies that the allow and exclude lists are combined, with the exclude list taking precedence.
+    #[test]
+    fn test_allow_and_exclude() {
+        let filter = TargetFilter::new(Some(&["app"]), Some(&["app::noisy"]));
+
+        assert!(filter.is_enabled("app::handler"));
+        assert!(!filter.is_enabled("app::noisy::loop"));
+        assert!(!filter.is_enabled("hyper"));
+        assert!(TargetFilter::new(None, None).is_enabled("anything"));
+    }
+}
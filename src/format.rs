@@ -0,0 +1,343 @@
+//! Rendering of captured records into the configured output formats.
+use std::borrow::Cow;
+use std::fmt::Write;
+
+use crate::config::{self, Config, Escaping, OutputFormat};
+use crate::record::{hostname, LogRecord};
+use crate::template::{default_template, CompiledTemplate};
+
+/// Renders records into every `OutputFormat`. Created once in `Logger::new`.
+pub(crate) struct Formatter {
+    line_template: CompiledTemplate,
+    show_source_location: bool,
+    show_module_path: bool,
+    show_thread_name: bool,
+    show_thread_id: bool,
+    show_process_id: bool,
+}
+
+impl Formatter {
+    /// # Panics
+    /// This function will panic if the custom line template is invalid.
+    pub fn new(config: &Config) -> Formatter {
+        let line_template = match &config.line_template {
+            config::LineTemplate::Default => CompiledTemplate::parse(&default_template(config)),
+            config::LineTemplate::Custom(template) => CompiledTemplate::parse(template),
+        }.unwrap_or_else(|err| {
+            panic!("Invalid line template: {}", err);
+        });
+
+        Formatter {
+            line_template,
+            show_source_location: config.source_location != config::SourceLocation::Hidden,
+            show_module_path: config.show_module_path == config::ShowModulePath::True,
+            show_thread_name: matches!(config.thread_info, config::ThreadInfo::Name | config::ThreadInfo::NameAndId),
+            show_thread_id: matches!(config.thread_info, config::ThreadInfo::Id | config::ThreadInfo::NameAndId),
+            show_process_id: config.show_process_id == config::ShowProcessId::True,
+        }
+    }
+
+    /// Renders the record as a single line, including the trailing newline.
+    /// The escaping policy only applies to the plain text format, the others always escape their values.
+    pub fn render(&self, format: &OutputFormat, escaping: &Escaping, record: &LogRecord) -> String {
+        match format {
+            OutputFormat::PlainText => self.line_template.render(record, escaping),
+            OutputFormat::Logfmt => self.render_logfmt(record),
+            OutputFormat::Gelf => {
+                let mut line = self.render_gelf(record);
+                line.push('\n');
+                line
+            },
+        }
+    }
+
+    /// Renders `ts=... level=info target=... msg="..."` followed by the optional fields and the record key-values.
+    fn render_logfmt(&self, record: &LogRecord) -> String {
+        let mut line = String::with_capacity(128);
+
+        if let Some(time) = &record.time {
+            push_logfmt_pair(&mut line, "ts", time);
+        }
+        push_logfmt_pair(&mut line, "level", &record.level.as_str().to_lowercase());
+        push_logfmt_pair(&mut line, "target", &record.target);
+        if self.show_module_path {
+            push_logfmt_pair(&mut line, "module", record.module_path.as_deref().unwrap_or_default());
+        }
+        if self.show_source_location {
+            push_logfmt_pair(&mut line, "file", record.file.as_deref().unwrap_or_default());
+            push_logfmt_pair(&mut line, "line", &record.line.map(|line| line.to_string()).unwrap_or_default());
+        }
+        if self.show_thread_name {
+            push_logfmt_pair(&mut line, "thread", record.thread_name.as_deref().unwrap_or("<unnamed>"));
+        }
+        if self.show_thread_id {
+            push_logfmt_pair(&mut line, "thread_id", &record.thread_id);
+        }
+        if self.show_process_id {
+            push_logfmt_pair(&mut line, "pid", &record.process_id.to_string());
+        }
+        push_logfmt_pair(&mut line, "msg", &record.message);
+
+        for (key, value) in &record.key_values {
+            push_logfmt_pair(&mut line, &reserved_key(key, &LOGFMT_RESERVED_FIELDS), value);
+        }
+
+        line.push('\n');
+        line
+    }
+
+    /// Renders a GELF 1.1 JSON payload, without a trailing newline.
+    pub fn render_gelf(&self, record: &LogRecord) -> String {
+        let mut json = String::with_capacity(256);
+        json.push_str("{\"version\":\"1.1\",\"host\":");
+        push_json_string(&mut json, hostname());
+
+        // GELF requires a non-empty short message, so leading blank lines are skipped
+        let short_message = record.message.lines().find(|line| !line.trim().is_empty()).unwrap_or(EMPTY_SHORT_MESSAGE);
+        json.push_str(",\"short_message\":");
+        push_json_string(&mut json, short_message);
+        // The full message is only useful if the short one had to cut something off
+        if !record.message.is_empty() && short_message != record.message {
+            json.push_str(",\"full_message\":");
+            push_json_string(&mut json, &record.message);
+        }
+
+        let timestamp = record.timestamp.unix_timestamp_nanos() as f64 / 1e9;
+        let _ = write!(json, ",\"timestamp\":{:.3}", timestamp);
+        let _ = write!(json, ",\"level\":{}", syslog_severity(record.level));
+
+        json.push_str(",\"_target\":");
+        push_json_string(&mut json, &record.target);
+        if let Some(file) = &record.file {
+            json.push_str(",\"_file\":");
+            push_json_string(&mut json, file);
+        }
+        if let Some(line) = record.line {
+            let _ = write!(json, ",\"_line\":{}", line);
+        }
+        if let Some(module_path) = record.module_path.as_ref().filter(|_| self.show_module_path) {
+            json.push_str(",\"_module\":");
+            push_json_string(&mut json, module_path);
+        }
+        if self.show_thread_name {
+            json.push_str(",\"_thread\":");
+            push_json_string(&mut json, record.thread_name.as_deref().unwrap_or("<unnamed>"));
+        }
+        if self.show_thread_id {
+            json.push_str(",\"_thread_id\":");
+            push_json_string(&mut json, &record.thread_id);
+        }
+        if self.show_process_id {
+            let _ = write!(json, ",\"_pid\":{}", record.process_id);
+        }
+
+        for (key, value) in &record.key_values {
+            let _ = write!(json, ",\"{}\":", gelf_field_name(key));
+            push_json_string(&mut json, value);
+        }
+
+        json.push('}');
+        json
+    }
+}
+
+fn push_logfmt_pair(line: &mut String, key: &str, value: &str) {
+    if !line.is_empty() {
+        line.push(' ');
+    }
+
+    // Keys can't be quoted in logfmt, so characters that would break the pair are replaced
+    if key.is_empty() {
+        line.push('_');
+    }
+    line.extend(key.chars().map(|c| if c <= ' ' || c == '=' || c == '"' { '_' } else { c }));
+    line.push('=');
+
+    let needs_quotes = value.is_empty() || value.chars().any(|c| c <= ' ' || c == '=' || c == '"' || c == '\\' || c.is_control());
+    if !needs_quotes {
+        line.push_str(value);
+        return;
+    }
+
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            },
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+/// Maps `log::Level` to the syslog severity used by the GELF `level` field.
+fn syslog_severity(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => 3,
+        log::Level::Warn => 4,
+        log::Level::Info => 6,
+        log::Level::Debug | log::Level::Trace => 7,
+    }
+}
+
+/// The `short_message` of records with an empty message.
+const EMPTY_SHORT_MESSAGE: &str = "(empty message)";
+
+/// The additional fields written for the record itself (`id` is reserved by GELF), see `reserved_key`.
+pub(crate) const GELF_RESERVED_FIELDS: [&str; 9] = ["id", "target", "file", "line", "module", "thread", "thread_id", "pid", "sha256"];
+
+/// The logfmt keys written for the record itself, see `reserved_key`.
+pub(crate) const LOGFMT_RESERVED_FIELDS: [&str; 11] = ["ts", "level", "target", "module", "file", "line", "thread", "thread_id", "pid", "msg", "sha256"];
+
+/// Key-values whose name is one of the `reserved` fields after any leading underscores get another underscore,
+/// so they don't clash with the fields written for the record itself. `unreserved_key` removes it again.
+pub(crate) fn reserved_key<'a>(key: &'a str, reserved: &[&str]) -> Cow<'a, str> {
+    if reserved.contains(&key.trim_start_matches('_')) {
+        Cow::Owned(format!("_{}", key))
+    } else {
+        Cow::Borrowed(key)
+    }
+}
+
+/// Reverses `reserved_key`.
+pub(crate) fn unreserved_key<'a>(key: &'a str, reserved: &[&str]) -> &'a str {
+    match key.strip_prefix('_') {
+        Some(stripped) if reserved.contains(&stripped.trim_start_matches('_')) => stripped,
+        _ => key,
+    }
+}
+
+/// GELF additional field names must match `^_[\w\.\-]*$`.
+fn gelf_field_name(key: &str) -> String {
+    let key = key.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' { c } else { '_' }).collect::<String>();
+    format!("_{}", reserved_key(&key, &GELF_RESERVED_FIELDS))
+}
+
+fn push_json_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            },
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use super::*;
+
+    // Verifies the logfmt layout and that simple values are written unquoted.
+    #[test]
+    fn test_logfmt_layout() {
+        let mut record = LogRecord::test("app::db", "connected");
+        record.key_values.push((String::from("request_id"), String::from("abc")));
+
+        assert_eq!(Formatter::new(&Config::default()).render_logfmt(&record), "ts=2024-01-01T00:00:00Z level=info target=app::db msg=connected request_id=abc\n");
+    }
+
+    // Verifies quoting and escaping of values and sanitizing of keys.
+    #[test]
+    fn test_logfmt_escaping() {
+        let mut record = LogRecord::test("app::db", "said \"hi\"\nC:\\temp");
+        record.key_values.push((String::from("bad key"), String::new()));
+
+        assert_eq!(Formatter::new(&Config::default()).render_logfmt(&record), "ts=2024-01-01T00:00:00Z level=info target=app::db msg=\"said \\\"hi\\\"\\nC:\\\\temp\" bad_key=\"\"\n");
+    }
+
+    // Verifies that key-values can't clash with the logfmt keys of the record itself.
+    #[test]
+    fn test_logfmt_reserved_keys() {
+        let mut record = LogRecord::test("app::db", "connected");
+        for key in ["msg", "_msg", "level", "user"] {
+            record.key_values.push((String::from(key), String::from("x")));
+        }
+
+        assert!(Formatter::new(&Config::default()).render_logfmt(&record).ends_with(" msg=connected _msg=x __msg=x _level=x user=x\n"));
+    }
+
+    // Verifies that logfmt includes the module path and source location when enabled.
+    #[test]
+    fn test_logfmt_location() {
+        let mut record = LogRecord::test("app::db", "connected");
+        record.module_path = Some(String::from("app::db"));
+        record.file = Some(String::from("src/db.rs"));
+        record.line = Some(42);
+
+        let formatter = Formatter::new(&Config {
+            source_location: config::SourceLocation::RelativePath,
+            show_module_path: config::ShowModulePath::True,
+            ..Default::default()
+        });
+
+        assert_eq!(formatter.render_logfmt(&record), "ts=2024-01-01T00:00:00Z level=info target=app::db module=app::db file=src/db.rs line=42 msg=connected\n");
+    }
+
+    // Verifies that logfmt and GELF include the thread and process information when enabled.
+    #[test]
+    fn test_thread_info() {
+        let mut record = LogRecord::test("app::db", "connected");
+        record.thread_name = Some(String::from("worker"));
+        record.thread_id = String::from("7");
+        record.process_id = 1234;
+
+        let formatter = Formatter::new(&Config {
+            thread_info: config::ThreadInfo::NameAndId,
+            show_process_id: config::ShowProcessId::True,
+            ..Default::default()
+        });
+
+        assert!(formatter.render_logfmt(&record).ends_with(" target=app::db thread=worker thread_id=7 pid=1234 msg=connected\n"));
+        assert!(formatter.render_gelf(&record).ends_with(",\"_target\":\"app::db\",\"_thread\":\"worker\",\"_thread_id\":\"7\",\"_pid\":1234}"));
+    }
+
+    // Verifies the GELF payload fields and the level mapping.
+    #[test]
+    fn test_gelf_payload() {
+        let mut record = LogRecord::test("app::db", "first line\nsecond \"line\"");
+        record.level = Level::Warn;
+        record.file = Some(String::from("src/db.rs"));
+        record.line = Some(42);
+        record.key_values.push((String::from("id"), String::from("7")));
+
+        let payload = Formatter::new(&Config::default()).render_gelf(&record);
+
+        assert!(payload.starts_with("{\"version\":\"1.1\",\"host\":"));
+        assert!(payload.contains(",\"short_message\":\"first line\",\"full_message\":\"first line\\nsecond \\\"line\\\"\",\"timestamp\":0.000,\"level\":4,"));
+        assert!(payload.ends_with(",\"_target\":\"app::db\",\"_file\":\"src/db.rs\",\"_line\":42,\"__id\":\"7\"}"));
+    }
+
+    // Verifies that the short message is never empty and key-values can't clash with the record fields.
+    #[test]
+    fn test_gelf_edge_cases() {
+        let formatter = Formatter::new(&Config::default());
+
+        let payload = formatter.render_gelf(&LogRecord::test("app::db", ""));
+        assert!(payload.contains(",\"short_message\":\"(empty message)\",\"timestamp\""));
+
+        let payload = formatter.render_gelf(&LogRecord::test("app::db", "\n  \nstack trace"));
+        assert!(payload.contains(",\"short_message\":\"stack trace\",\"full_message\":\"\\n  \\nstack trace\""));
+
+        let mut record = LogRecord::test("app::db", "clash");
+        record.key_values.push((String::from("target"), String::from("other")));
+        record.key_values.push((String::from("pid"), String::from("2")));
+        record.key_values.push((String::from("_pid"), String::from("3")));
+        let payload = formatter.render_gelf(&record);
+        assert!(payload.ends_with(",\"_target\":\"app::db\",\"__target\":\"other\",\"__pid\":\"2\",\"___pid\":\"3\"}"));
+    }
+}
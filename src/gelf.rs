@@ -0,0 +1,145 @@
+//! UDP transport for GELF payloads, see `config::GelfOutput`.
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use time::OffsetDateTime;
+
+/// Every chunk starts with the magic bytes, an 8 byte message id, the sequence number and the sequence count.
+const CHUNK_HEADER_SIZE: usize = 12;
+const CHUNK_MAGIC: [u8; 2] = [0x1e, 0x0f];
+/// Graylog discards messages that are split into more chunks than this.
+const MAX_CHUNKS: usize = 128;
+
+pub(crate) struct GelfUdpSender {
+    socket: UdpSocket,
+    max_chunk_size: usize,
+    message_counter: AtomicU64,
+}
+
+impl GelfUdpSender {
+    /// Binds an ephemeral local socket and connects it to `address`.
+    pub fn connect(address: &str, max_chunk_size: usize) -> io::Result<GelfUdpSender> {
+        if max_chunk_size <= CHUNK_HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("GELF chunk size must be larger than {} bytes", CHUNK_HEADER_SIZE)));
+        }
+
+        let remote = address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Could not resolve {}", address))
+        })?;
+        let socket = UdpSocket::bind(if remote.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.connect(remote)?;
+
+        Ok(GelfUdpSender {
+            socket,
+            max_chunk_size,
+            message_counter: AtomicU64::new(0),
+        })
+    }
+
+    /// Sends the payload as a single datagram, or as chunked GELF if it doesn't fit into one.
+    pub fn send(&self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() <= self.max_chunk_size {
+            self.socket.send(payload)?;
+            return Ok(());
+        }
+
+        let chunk_data_size = self.max_chunk_size - CHUNK_HEADER_SIZE;
+        let chunk_count = payload.len().div_ceil(chunk_data_size);
+        if chunk_count > MAX_CHUNKS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("GELF message of {} bytes needs more than {} chunks", payload.len(), MAX_CHUNKS)));
+        }
+
+        let message_id = self.next_message_id();
+        let mut datagram = Vec::with_capacity(self.max_chunk_size);
+        for (sequence, chunk) in payload.chunks(chunk_data_size).enumerate() {
+            datagram.clear();
+            datagram.extend_from_slice(&CHUNK_MAGIC);
+            datagram.extend_from_slice(&message_id);
+            datagram.push(sequence as u8);
+            datagram.push(chunk_count as u8);
+            datagram.extend_from_slice(chunk);
+
+            self.socket.send(&datagram)?;
+        }
+
+        Ok(())
+    }
+
+    /// Message ids only have to be unique for a few seconds per sender, so a mix of the
+    /// current time, the process id and a counter is sufficient.
+    fn next_message_id(&self) -> [u8; 8] {
+        let counter = self.message_counter.fetch_add(1, Ordering::Relaxed);
+        let nanos = OffsetDateTime::now_utc().unix_timestamp_nanos() as u64;
+
+        // splitmix64 finalizer
+        let mut id = nanos ^ counter.rotate_left(32) ^ u64::from(std::process::id());
+        id = (id ^ (id >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        id = (id ^ (id >> 27)).wrapping_mul(0x94d049bb133111eb);
+        (id ^ (id >> 31)).to_be_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    fn receiver() -> (UdpSocket, String) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+
+        (socket, address)
+    }
+
+    // Verifies that small payloads are sent as a single, unchunked datagram.
+    #[test]
+    fn test_single_datagram() {
+        let (receiver, address) = receiver();
+        let sender = GelfUdpSender::connect(&address, 1024).unwrap();
+
+        sender.send(b"{\"short_message\":\"hi\"}").unwrap();
+
+        let mut buffer = [0u8; 2048];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"{\"short_message\":\"hi\"}");
+    }
+
+    // Verifies that large payloads are chunked and can be reassembled from the chunk headers.
+    #[test]
+    fn test_chunked_datagrams() {
+        let (receiver, address) = receiver();
+        let sender = GelfUdpSender::connect(&address, 32).unwrap();
+        let payload: Vec<u8> = (0..100u8).collect();
+
+        sender.send(&payload).unwrap();
+
+        let mut chunks = vec![];
+        let mut buffer = [0u8; 64];
+        for _ in 0..5 {
+            let len = receiver.recv(&mut buffer).unwrap();
+            chunks.push(buffer[..len].to_vec());
+        }
+
+        let message_id = &chunks[0][2..10];
+        let mut reassembled = vec![Vec::new(); 5];
+        for chunk in &chunks {
+            assert_eq!(&chunk[..2], &CHUNK_MAGIC);
+            assert_eq!(&chunk[2..10], message_id);
+            assert_eq!(chunk[11], 5);
+            reassembled[chunk[10] as usize] = chunk[12..].to_vec();
+        }
+
+        assert_eq!(reassembled.concat(), payload);
+    }
+
+    // Verifies that payloads needing more than 128 chunks are rejected instead of being truncated.
+    #[test]
+    fn test_too_many_chunks() {
+        let (_receiver, address) = receiver();
+        let sender = GelfUdpSender::connect(&address, 13).unwrap();
+
+        assert!(sender.send(&[0u8; 129]).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+//! Tamper-evident log files, see `config::HashChain`.
+//!
+//! With the hash chain enabled, every record written to a log file carries an HMAC-SHA256 of the previous hash
+//! followed by the record, keyed with the configured key (the first record of a chain uses 32 zero bytes as the
+//! previous hash). Plain text lines end with ` #sha256=<hash>`, logfmt lines with a `sha256=<hash>` field and GELF
+//! messages get a `_sha256` field. The chain continues into the next file when `SplitLogFiles` rotates the log, so
+//! rotated files have to be verified in order.
+//!
+//! Editing, removing or reordering a record changes every hash after it, and only someone holding the key can
+//! compute the new hashes. Keep the key away from the machine that writes the logs, or at least from anyone who
+//! can edit them: with the key, a whole file can be rewritten with a valid chain. Records removed from the end of
+//! the last file can't be detected from the file alone either. Store the hash returned by `Verifier::last_hash`
+//! somewhere else to detect that as well.
+//!
+//! # Examples
+//! ```no_run
+//! use logpeek::config::OutputFormat;
+//! use logpeek::hash_chain::Verifier;
+//!
+//! let key = b"load this from your secret store";
+//! let mut verifier = Verifier::new(key, OutputFormat::PlainText);
+//! for path in ["logs/2024_01_01_00_00_00.log", "logs/2024_01_02_00_00_00.log"] {
+//!     if let Err(err) = verifier.verify_file(path) {
+//!         eprintln!("{}: {}", path, err);
+//!     }
+//! }
+//! ```
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::OutputFormat;
+
+pub type Hash = [u8; 32];
+
+/// Separates a plain text record from its hash.
+const PLAIN_TEXT_SUFFIX: &str = " #sha256=";
+/// The field holding the hash of a logfmt record.
+const LOGFMT_SUFFIX: &str = " sha256=";
+/// The start of the field holding the hash of a GELF message, which replaces its closing brace.
+const GELF_FIELD: &str = ",\"_sha256\":\"";
+
+/// How much of an existing file is read to find the hash its chain continues from.
+const TAIL_LEN: u64 = 64 * 1024;
+
+/// The HMAC-SHA256 of the previous hash followed by the entry.
+fn next_hash(key: &[u8], previous: &Hash, entry: &[u8]) -> Hash {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(previous);
+    mac.update(entry);
+    mac.finalize().into_bytes().into()
+}
+
+fn to_hex(hash: &Hash) -> String {
+    hash.iter().fold(String::with_capacity(64), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+fn from_hex(hex: &[u8]) -> Option<Hash> {
+    if hex.len() != 64 {
+        return None;
+    }
+
+    let mut hash = [0; 32];
+    for (byte, digits) in hash.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Adds the hash to `message`, which is a record rendered in `format`. Returns the line and the new hash.
+/// The hash covers the record exactly as it was rendered, without its trailing line break.
+pub(crate) fn chain_line(key: &[u8], previous: &Hash, message: &str, format: OutputFormat) -> (String, Hash) {
+    let entry = message.strip_suffix('\n').unwrap_or(message);
+    let hash = next_hash(key, previous, entry.as_bytes());
+    let hex = to_hex(&hash);
+
+    let mut line = String::with_capacity(message.len() + GELF_FIELD.len() + 67);
+    match format {
+        OutputFormat::Gelf if entry.ends_with('}') => {
+            line.push_str(&entry[..entry.len() - 1]);
+            line.push_str(GELF_FIELD);
+            line.push_str(&hex);
+            line.push_str("\"}");
+        },
+        OutputFormat::Logfmt => {
+            line.push_str(entry);
+            line.push_str(LOGFMT_SUFFIX);
+            line.push_str(&hex);
+        },
+        _ => {
+            line.push_str(entry);
+            line.push_str(PLAIN_TEXT_SUFFIX);
+            line.push_str(&hex);
+        },
+    }
+    if entry.len() != message.len() {
+        line.push('\n');
+    }
+    (line, hash)
+}
+
+/// Splits a line without its line break into the record as it was hashed and the hash.
+/// Returns `None` for lines without a hash, e.g. continuation lines.
+fn split_hash(line: &[u8], format: OutputFormat) -> Option<(Vec<u8>, Hash)> {
+    let (entry, hex) = match format {
+        OutputFormat::Gelf => {
+            let rest = line.strip_suffix(b"\"}")?;
+            let field_start = rest.len().checked_sub(GELF_FIELD.len() + 64)?;
+            let (entry, field) = rest.split_at(field_start);
+            let hex = field.strip_prefix(GELF_FIELD.as_bytes())?;
+            return Some(([entry, b"}"].concat(), from_hex(hex)?));
+        },
+        OutputFormat::Logfmt => line.split_at(line.len().checked_sub(LOGFMT_SUFFIX.len() + 64)?),
+        OutputFormat::PlainText => line.split_at(line.len().checked_sub(PLAIN_TEXT_SUFFIX.len() + 64)?),
+    };
+
+    let suffix = if format == OutputFormat::Logfmt { LOGFMT_SUFFIX } else { PLAIN_TEXT_SUFFIX };
+    let hex = hex.strip_prefix(suffix.as_bytes())?;
+    Some((entry.to_vec(), from_hex(hex)?))
+}
+
+/// Returns the hash of the last record in an existing log file, so that appending to it continues its chain.
+pub(crate) fn last_hash(path: &Path, format: OutputFormat) -> io::Result<Option<Hash>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(TAIL_LEN)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+
+    Ok(tail.split(|byte| *byte == b'\n').rev().find_map(|line| split_hash(line, format)).map(|(_, hash)| hash))
+}
+
+/// Why a log file failed verification.
+#[derive(Debug)]
+pub enum VerifyError {
+    Io(io::Error),
+    /// The record ending at this line (starting at 1) doesn't match its hash. It, or a record before it,
+    /// was edited, removed or reordered, or the file was verified with the wrong key.
+    Mismatch { line: usize },
+    /// The file ends with lines that don't belong to a hashed record.
+    Unhashed { line: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Io(err) => write!(f, "failed to read log file: {}", err),
+            VerifyError::Mismatch { line } => write!(f, "hash chain broken at line {}", line),
+            VerifyError::Unhashed { line } => write!(f, "lines without a hash from line {}", line),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl From<io::Error> for VerifyError {
+    fn from(err: io::Error) -> Self {
+        VerifyError::Io(err)
+    }
+}
+
+/// Checks the hash chain of one or more log files. Files verified with the same `Verifier` have to form
+/// one chain, e.g. the files of a rotated log in the order they were written.
+pub struct Verifier {
+    key: Vec<u8>,
+    format: OutputFormat,
+    last_hash: Hash,
+}
+
+impl Verifier {
+    /// A verifier for files written in `format` with `HashChain::HmacSha256 { key }`, for a chain that starts
+    /// with the first record of the first file.
+    pub fn new(key: &[u8], format: OutputFormat) -> Verifier {
+        Verifier { key: key.to_vec(), format, last_hash: [0; 32] }
+    }
+
+    /// Continues the chain from `hash`, e.g. to verify a single rotated file with the last hash of the file before it.
+    pub fn starting_at(mut self, hash: Hash) -> Verifier {
+        self.last_hash = hash;
+        self
+    }
+
+    /// The hash of the last verified record.
+    pub fn last_hash(&self) -> Hash {
+        self.last_hash
+    }
+
+    pub fn verify_file(&mut self, path: impl AsRef<Path>) -> Result<(), VerifyError> {
+        self.verify(BufReader::new(File::open(path)?))
+    }
+
+    /// Verifies the records read from `reader`. Lines without a hash are continuation lines
+    /// of the next record, as written by `Escaping::Indent`, `Escaping::Marker` or `Escaping::None`.
+    pub fn verify(&mut self, mut reader: impl BufRead) -> Result<(), VerifyError> {
+        let mut entry = Vec::new();
+        let mut entry_start = None;
+        let mut line = Vec::new();
+        let mut line_number = 0;
+
+        // Lines are read as raw bytes, so that a `\r` that is part of a record is hashed like it was written
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            line_number += 1;
+
+            match split_hash(line.strip_suffix(b"\n").unwrap_or(&line), self.format) {
+                Some((record, hash)) => {
+                    entry.extend_from_slice(&record);
+                    if next_hash(&self.key, &self.last_hash, &entry) != hash {
+                        return Err(VerifyError::Mismatch { line: line_number });
+                    }
+                    self.last_hash = hash;
+                    entry.clear();
+                    entry_start = None;
+                },
+                None => {
+                    entry.extend_from_slice(&line);
+                    entry_start.get_or_insert(line_number);
+                },
+            }
+            line.clear();
+        }
+
+        match entry_start {
+            Some(line) => Err(VerifyError::Unhashed { line }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"secret";
+
+    fn chain(messages: &[&str], format: OutputFormat) -> String {
+        let mut hash = [0; 32];
+        messages.iter().map(|message| {
+            let (line, next) = chain_line(KEY, &hash, message, format);
+            hash = next;
+            line
+        }).collect()
+    }
+
+    fn verify(contents: &str) -> Result<(), VerifyError> {
+        Verifier::new(KEY, OutputFormat::PlainText).verify(contents.as_bytes())
+    }
+
+    // Verifies that an untouched chain, including multi-line records and line breaks with `\r`, passes.
+    #[test]
+    fn test_valid_chain() {
+        assert!(verify(&chain(&["first\n", "second\n    continued\n", "raw\r\nwindows\r\n", "third\n"], OutputFormat::PlainText)).is_ok());
+    }
+
+    // Verifies that edited, removed and reordered records are detected at the right line.
+    #[test]
+    fn test_tampering_detected() {
+        let contents = chain(&["first\n", "second\n", "third\n"], OutputFormat::PlainText);
+        let lines = contents.lines().collect::<Vec<_>>();
+
+        let edited = contents.replace("second", "secund");
+        assert!(matches!(verify(&edited), Err(VerifyError::Mismatch { line: 2 })));
+
+        let removed = format!("{}\n{}\n", lines[0], lines[2]);
+        assert!(matches!(verify(&removed), Err(VerifyError::Mismatch { line: 2 })));
+
+        let reordered = format!("{}\n{}\n{}\n", lines[1], lines[0], lines[2]);
+        assert!(matches!(verify(&reordered), Err(VerifyError::Mismatch { line: 1 })));
+
+        let appended = format!("{}forged\n", contents);
+        assert!(matches!(verify(&appended), Err(VerifyError::Unhashed { line: 4 })));
+    }
+
+    // Verifies that a chain recomputed without the key doesn't pass.
+    #[test]
+    fn test_forged_chain_without_key() {
+        let mut hash = [0; 32];
+        let forged = ["first\n", "forged\n"].iter().map(|message| {
+            let (line, next) = chain_line(b"guessed", &hash, message, OutputFormat::PlainText);
+            hash = next;
+            line
+        }).collect::<String>();
+
+        assert!(matches!(verify(&forged), Err(VerifyError::Mismatch { line: 1 })));
+    }
+
+    // Verifies that the hash is a field in structured formats, so their lines stay valid.
+    #[test]
+    fn test_structured_formats() {
+        let gelf = chain(&["{\"short_message\":\"first\"}\n", "{\"short_message\":\"second\"}\n"], OutputFormat::Gelf);
+        assert!(gelf.lines().all(|line| line.starts_with("{\"short_message\":\"") && line.contains("\",\"_sha256\":\"") && line.ends_with("\"}")));
+        assert!(Verifier::new(KEY, OutputFormat::Gelf).verify(gelf.as_bytes()).is_ok());
+        assert!(Verifier::new(KEY, OutputFormat::Gelf).verify(gelf.replace("first", "frist").as_bytes()).is_err());
+
+        let logfmt = chain(&["level=info msg=first\n"], OutputFormat::Logfmt);
+        assert!(logfmt.starts_with("level=info msg=first sha256="));
+        assert!(Verifier::new(KEY, OutputFormat::Logfmt).verify(logfmt.as_bytes()).is_ok());
+    }
+
+    // Verifies that a chain continues across files.
+    #[test]
+    fn test_chain_across_files() {
+        let contents = chain(&["first\n", "second\n", "third\n"], OutputFormat::PlainText);
+        let (first_file, second_file) = contents.split_at(contents.find("second").unwrap());
+
+        let mut verifier = Verifier::new(KEY, OutputFormat::PlainText);
+        verifier.verify(first_file.as_bytes()).unwrap();
+        let last_hash = verifier.last_hash();
+        verifier.verify(second_file.as_bytes()).unwrap();
+
+        assert!(Verifier::new(KEY, OutputFormat::PlainText).verify(second_file.as_bytes()).is_err());
+        assert!(Verifier::new(KEY, OutputFormat::PlainText).starting_at(last_hash).verify(second_file.as_bytes()).is_ok());
+    }
+}
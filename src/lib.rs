@@ -26,8 +26,32 @@ use log::SetLoggerError;
 use config::Config;
 use logger::Logger;
 
+pub use logger::colorize;
+
 pub mod config;
+pub mod context;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+mod escape;
+mod filter;
+mod format;
+mod gelf;
+#[cfg(feature = "hash-chain")]
+pub mod hash_chain;
 mod logger;
+pub mod merge;
+#[cfg(feature = "regex")]
+mod message_filter;
+mod output;
+pub mod query;
+pub mod reader;
+mod record;
+pub mod redact;
+mod routing;
+mod sampling;
+pub mod stats;
+mod template;
+mod throttle;
 
 /// Initializes the logger by setting it as the global boxed logger for the `log` crate.
 ///
@@ -43,9 +67,21 @@ mod logger;
 ///
 /// This function will panic if it fails to create the log directory or the log file.
 /// This can happen if the user does not have the required permissions.
+/// It will also panic if the line template, a continuation marker, a message filter or a redaction pattern is invalid,
+/// if a sampling rate is NaN, if the `per_second` of the rate limit isn't greater than 0, or if the GELF address
+/// can't be resolved.
 pub fn init(config: Config) -> Result<(), SetLoggerError> {
-    log::set_max_level(config.min_log_level);
+    let logger = Logger::new(config);
+    let counters = logger.counters();
+    log::set_max_level(logger.max_level());
 
-    log::set_boxed_logger(Box::new(Logger::new(config)))?;
+    log::set_boxed_logger(Box::new(logger))?;
+    stats::register(counters);
     Ok(())
+}
+
+/// Returns the counters of the logger installed by `init`, see the `stats` module.
+/// All counters are zero if `init` hasn't been called successfully.
+pub fn stats() -> stats::Stats {
+    stats::registered()
 }
\ No newline at end of file
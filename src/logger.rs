@@ -1,49 +1,49 @@
 //! Logger implementation
-use std::{fs, io};
-use std::fs::File;
-use std::io::{BufWriter, stderr, stdout, Write};
+use std::cell::Cell;
+use std::io::{stderr, stdout, Write};
 use std::path::PathBuf;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicU64;
+use std::time::Instant;
 
-use colored::Colorize;
-use log::{error, Log, Metadata, Record};
+use colored::{ColoredString, Colorize};
+use log::{error, LevelFilter, Log, Metadata, Record};
 use time::{format_description, OffsetDateTime};
 use time::format_description::FormatItem;
 use time::format_description::well_known::{Iso8601, Rfc2822, Rfc3339};
 
 use crate::{Config, config};
 use crate::config::OutputDirName;
+use crate::filter::TargetFilter;
+use crate::format::Formatter;
+use crate::gelf::GelfUdpSender;
+#[cfg(feature = "regex")]
+use crate::message_filter::MessageFilters;
+use crate::output::Output;
+use crate::record::{shorten_path, LogRecord};
+use crate::redact::Redactions;
+use crate::routing::{RouteOutput, Router};
+use crate::sampling::Sampler;
+use crate::stats::{self, Counters, Stats};
+use crate::throttle::{DuplicateDecision, DuplicateSuppressor, RateDecision, RateLimiter};
 
 pub struct Logger {
     output_lock: Mutex<Option<Output>>,
     config: Config,
     custom_time_format: Option<Vec<FormatItem<'static>>>,
-}
-
-enum OutputContainer {
-    File(File),
-    Buffered(BufWriter<File>)
-}
-
-struct Output {
-    container: OutputContainer,
-    file_size: u64,
-}
-
-impl Write for OutputContainer {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self {
-            OutputContainer::File(file) => file.write(buf),
-            OutputContainer::Buffered(buffer) => buffer.write(buf),
-        }
-    }
-
-    fn flush(&mut self) -> io::Result<()> {
-        match self {
-            OutputContainer::File(file) => file.flush(),
-            OutputContainer::Buffered(buffer) => buffer.flush(),
-        }
-    }
+    formatter: Formatter,
+    gelf_sender: Option<GelfUdpSender>,
+    target_filter: TargetFilter,
+    #[cfg(feature = "regex")]
+    message_filters: MessageFilters,
+    rate_limiter: Option<RateLimiter>,
+    duplicate_suppressor: Option<DuplicateSuppressor>,
+    sampler: Option<Sampler>,
+    router: Router,
+    max_level: LevelFilter,
+    redactions: Option<Redactions>,
+    counters: Arc<Counters>,
+    stats_emitted: Mutex<Instant>,
 }
 
 impl Logger {
@@ -55,7 +55,10 @@ impl Logger {
     /// # Panics
     /// This function will panic if it fails to create the log directory or the log file.
     /// This can happen if the user does not have the required permissions.
-    pub fn new(config: Config) -> Logger {
+    /// It will also panic if the line template, a continuation marker, a message filter or a redaction pattern is invalid,
+    /// if a sampling rate is NaN, if the `per_second` of the rate limit isn't greater than 0, or if the GELF address
+    /// can't be resolved.
+    pub fn new(mut config: Config) -> Logger {
         let output_handle = match config.logging_mode {
             config::LoggingMode::File | config::LoggingMode::FileAndConsole => {
                 Some(Logger::output_file_setup(&config))
@@ -72,89 +75,261 @@ impl Logger {
             _ => None,
         };
 
-        Logger {
-            output_lock: Mutex::new(output_handle),
-            config,
-            custom_time_format
+        for escaping in [&config.file_escaping, &config.console_escaping] {
+            if let config::Escaping::Marker(marker) = escaping {
+                if marker.is_empty() || marker.contains(['\n', '\r']) {
+                    panic!("Invalid continuation marker {:?}, it must be a non-empty single line", marker);
+                }
+            }
         }
-    }
 
-    fn output_file_setup(config: &Config) -> Output {
-        let log_path = Logger::get_log_pathbuf(config);
+        let formatter = Formatter::new(&config);
+
+        let gelf_sender = match &config.gelf_output {
+            config::GelfOutput::Udp { address, max_chunk_size } => {
+                Some(GelfUdpSender::connect(address, *max_chunk_size).unwrap_or_else(|err| {
+                    panic!("Failed to set up GELF output to {}: {}", address, err)
+                }))
+            },
+            config::GelfOutput::Disabled => None,
+        };
+
+        let target_filter = TargetFilter::new(config.target_allow.as_deref(), config.target_filter.as_deref());
 
-        fs::create_dir_all(log_path.parent().unwrap_or_else(|| {
-            panic!("Failed to get parent directory for log path: {:?}", log_path)
-        })).unwrap_or_else(|err| {
-            panic!("Failed to create log directory at {:?}: {}", log_path, err)
+        #[cfg(feature = "regex")]
+        let message_filters = MessageFilters::new(&config.message_filters).unwrap_or_else(|err| {
+            panic!("Invalid message filter pattern: {}", err);
         });
 
-        let file = File::options()
-            .append(true)
-            .create(true)
-            .open(&log_path)
-            .unwrap_or_else(|err| {
-                panic!("Failed to open log file at {:?}: {}", log_path, err)
-            });
+        let redactions = Redactions::new(std::mem::take(&mut config.redaction)).unwrap_or_else(|err| {
+            panic!("Invalid redaction pattern: {}", err);
+        });
 
-        let output_container = if config.logging_strategy == config::LoggingStrategy::Asynchronous {
-            OutputContainer::Buffered(BufWriter::new(file))
+        let sampler = Sampler::new(&config.sampling);
+        let rate_limiter = RateLimiter::new(&config.rate_limit);
+        let duplicate_suppressor = if config.suppress_duplicates == config::SuppressDuplicates::True {
+            Some(DuplicateSuppressor::default())
         } else {
-            OutputContainer::File(file)
+            None
         };
 
-        Output {
-            container: output_container,
-            file_size: 0,
+        let router = Router::new(&config, output_handle.is_some(), |name, format| {
+            Output::open(&Logger::get_log_dir(&config).join(name), &config, format)
+        });
+        let max_level = config.min_log_level.max(router.max_level());
+        let counters = Arc::new(Counters::new(&config.recent_counts));
+
+        Logger {
+            output_lock: Mutex::new(output_handle),
+            config,
+            custom_time_format,
+            formatter,
+            gelf_sender,
+            target_filter,
+            #[cfg(feature = "regex")]
+            message_filters,
+            rate_limiter,
+            duplicate_suppressor,
+            sampler,
+            router,
+            max_level,
+            redactions,
+            counters,
+            stats_emitted: Mutex::new(Instant::now()),
         }
     }
 
+    /// The least severe level that any destination accepts.
+    pub fn max_level(&self) -> LevelFilter {
+        self.max_level
+    }
+
+    fn output_file_setup(config: &Config) -> Output {
+        Output::open(&Logger::get_log_pathbuf(config), config, config.file_format).indexed(config)
+    }
+
     /// Writes a message to the log file and/or the console.
     pub fn write(&self, message: &str, log_level: &log::Level) {
-        if self.config.logging_mode == config::LoggingMode::FileAndConsole || self.config.logging_mode == config::LoggingMode::Console {
-            let colored_message = if self.config.use_term_color == config::UseTermColor::True {
-                match log_level {
-                    log::Level::Error => message.red(),
-                    log::Level::Warn => message.yellow(),
-                    log::Level::Info => message.green(),
-                    log::Level::Debug => message.blue(),
-                    log::Level::Trace => message.magenta(),
-                }
-            } else {
-                message.normal()
-            };
-
-            match self.config.console_mode {
-                config::ConsoleMode::Stdout => write!(stdout(), "{}", colored_message).unwrap_or_else(|err| {
-                    error!("Failed to write to stdout {:?}", err);
-                }),
-                config::ConsoleMode::Stderr => write!(stderr(), "{}", colored_message).unwrap_or_else(|_| ()),
-                config::ConsoleMode::Mixed => {
-                    match log_level {
-                        log::Level::Error => write!(stderr(), "{}", colored_message).unwrap_or_else(|err| {
-                            error!("Failed to write to stdout {:?}", err);
-                        }),
-                        _ => write!(stdout(), "{}", colored_message).unwrap_or_else(|_| ()),
-                    }
-                }
+        if self.has_console_output() {
+            self.write_console(message, log_level);
+        }
+        self.write_file(message, log_level);
+    }
+
+    /// Renders the record in the format of each destination and writes it.
+    fn write_record(&self, record: &LogRecord) {
+        let routes = self.router.matching(record.level, &record.target).collect::<Vec<_>>();
+        // Records below `min_log_level` that only a route accepts, and `RouteMode::Only` records, stay out of the default destinations
+        let write_default = record.level <= self.config.min_log_level && !routes.iter().any(|route| route.only);
+        if write_default {
+            self.send_gelf(record);
+            self.write_default(record);
+        }
+
+        for route in routes {
+            match &route.output {
+                RouteOutput::Console => if !(write_default && self.has_console_output()) {
+                    self.write_console(&self.formatter.render(&route.format, &self.config.console_escaping, record), &record.level);
+                },
+                RouteOutput::MainFile => if !write_default {
+                    self.write_file(&self.formatter.render(&route.format, &self.config.file_escaping, record), &record.level);
+                },
+                RouteOutput::File(output) => {
+                    let message = self.formatter.render(&route.format, &self.config.file_escaping, record);
+                    let mut output = output.lock().unwrap_or_else(|err| err.into_inner());
+                    let written = output.write_message(&message, record.level, &self.config, &|notice| {
+                        self.render_notice(&route.format, notice)
+                    });
+                    self.count_file_write(&self.counters.route_file_bytes, written);
+                },
             }
         }
-        
+    }
+
+    fn send_gelf(&self, record: &LogRecord) {
+        if let Some(gelf_sender) = &self.gelf_sender {
+            let message = self.formatter.render_gelf(record);
+            // Logging the failure through `error!` would end up here again, so it goes straight to stderr
+            match gelf_sender.send(message.as_bytes()) {
+                Ok(()) => stats::add(&self.counters.gelf_bytes, message.len() as u64),
+                Err(err) => {
+                    stats::increment(&self.counters.write_errors);
+                    eprintln!("logpeek: failed to send GELF message: {}", err);
+                },
+            }
+        }
+    }
+
+    /// Writes the record to the destinations selected by `LoggingMode`.
+    fn write_default(&self, record: &LogRecord) {
+        if self.config.file_format == self.config.console_format && self.config.file_escaping == self.config.console_escaping {
+            self.write(&self.formatter.render(&self.config.file_format, &self.config.file_escaping, record), &record.level);
+            return;
+        }
+
+        if self.has_console_output() {
+            self.write_console(&self.formatter.render(&self.config.console_format, &self.config.console_escaping, record), &record.level);
+        }
+        if self.has_file_output() {
+            self.write_file(&self.formatter.render(&self.config.file_format, &self.config.file_escaping, record), &record.level);
+        }
+    }
+
+    /// Renders a notice from the logger itself, e.g. about records that are missing from a file.
+    fn render_notice(&self, format: &config::OutputFormat, message: String) -> String {
+        let mut record = LogRecord::capture(&Record::builder()
+            .args(format_args!("{}", message))
+            .level(log::Level::Warn)
+            .target("logpeek")
+            .build(), self.get_current_time().ok());
+        record.key_values.clear();
+
+        self.formatter.render(format, &self.config.file_escaping, &record)
+    }
+
+    fn count_file_write(&self, bytes: &AtomicU64, written: Option<usize>) {
+        match written {
+            Some(written) => stats::add(bytes, written as u64),
+            None => stats::increment(&self.counters.write_errors),
+        }
+    }
+
+    /// A snapshot of the counters of this logger.
+    pub fn stats(&self) -> Stats {
+        self.counters.snapshot()
+    }
+
+    pub(crate) fn counters(&self) -> Arc<Counters> {
+        Arc::clone(&self.counters)
+    }
+
+    /// Logs the counters as a record if `EmitStats` is due.
+    fn emit_stats(&self) {
+        let config::EmitStats::Every(interval) = self.config.emit_stats else { return };
+        // Another thread holding the lock is already emitting them
+        let Ok(mut last_emitted) = self.stats_emitted.try_lock() else { return };
+        if last_emitted.elapsed() < interval {
+            return;
+        }
+        *last_emitted = Instant::now();
+        drop(last_emitted);
+
+        let stats = self.stats();
+        let key_values = [
+            ("records", stats.records.total()),
+            ("errors", stats.records.error),
+            ("warnings", stats.records.warn),
+            ("filtered", stats.filtered),
+            ("console_bytes", stats.console_bytes),
+            ("file_bytes", stats.file_bytes),
+            ("route_file_bytes", stats.route_file_bytes),
+            ("gelf_bytes", stats.gelf_bytes),
+            ("write_errors", stats.write_errors),
+            ("rotations", stats.rotations),
+        ];
+        let message = key_values.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join(" ");
+        let mut record = LogRecord::capture(&Record::builder()
+            .args(format_args!("Logger stats: {}", message))
+            .level(log::Level::Info)
+            .target("logpeek::stats")
+            .build(), self.get_current_time().ok());
+        record.key_values.clear();
+
+        self.write_record(&record);
+    }
+
+    fn has_console_output(&self) -> bool {
+        self.config.logging_mode == config::LoggingMode::FileAndConsole || self.config.logging_mode == config::LoggingMode::Console
+    }
+
+    fn has_file_output(&self) -> bool {
+        self.config.logging_mode == config::LoggingMode::FileAndConsole || self.config.logging_mode == config::LoggingMode::File
+    }
+
+    fn write_console(&self, message: &str, log_level: &log::Level) {
+        let colored_message = if self.config.use_term_color == config::UseTermColor::True {
+            colorize(message, *log_level)
+        } else {
+            message.normal()
+        };
+
+        let to_stderr = match self.config.console_mode {
+            config::ConsoleMode::Stdout => false,
+            config::ConsoleMode::Stderr => true,
+            config::ConsoleMode::Mixed => *log_level == log::Level::Error,
+        };
+        let result = if to_stderr {
+            write!(stderr(), "{}", colored_message)
+        } else {
+            write!(stdout(), "{}", colored_message)
+        };
+
+        match result {
+            Ok(()) => stats::add(&self.counters.console_bytes, message.len() as u64),
+            Err(err) => {
+                stats::increment(&self.counters.write_errors);
+                // A failing stderr can't report its own failure
+                if !to_stderr {
+                    eprintln!("logpeek: failed to write to stdout: {}", err);
+                }
+            },
+        }
+    }
+
+    fn write_file(&self, message: &str, log_level: &log::Level) {
         let mut is_split = false;
         if let Some(output_handle) = self.output_lock.lock().unwrap_or_else(|err| {
-            error!("A thread panicked while holding the log file lock, using into_inner {:?}", err);
+            eprintln!("logpeek: a thread panicked while holding the log file lock, using into_inner: {}", err);
             err.into_inner()
         }).as_mut() {
-            if let Err(e) = output_handle.container.write(message.as_bytes()) {
-                error!("Failed to write to log file {:?}", e);
-            }
-
-            if self.config.split_log_files != config::SplitLogFiles::False {
-                output_handle.file_size += message.len() as u64;
+            let written = output_handle.write_message(message, *log_level, &self.config, &|notice| {
+                self.render_notice(&self.config.file_format, notice)
+            });
+            self.count_file_write(&self.counters.file_bytes, written);
 
-                if let config::SplitLogFiles::True(max_size) = self.config.split_log_files {
-                    if output_handle.file_size >= max_size {
-                        is_split = true;
-                    }
+            if let config::SplitLogFiles::True(max_size) = self.config.split_log_files {
+                if output_handle.file_size >= max_size {
+                    is_split = true;
                 }
             }
         }
@@ -166,15 +341,37 @@ impl Logger {
     }
 
     pub fn flush(&self) {
+        for summary in self.rate_limiter.as_ref().map(RateLimiter::take_summaries).unwrap_or_default() {
+            self.write_record(&summary);
+        }
+        if let Some(summary) = self.duplicate_suppressor.as_ref().and_then(|suppressor| suppressor.take_summary()) {
+            self.write_record(&summary);
+        }
+
         if let Some(output_handle) = self.output_lock.lock().unwrap_or_else(|err| {
-            error!("A thread panicked while holding the log file lock, using into_inner {:?}", err);
+            eprintln!("logpeek: a thread panicked while holding the log file lock, using into_inner: {}", err);
             err.into_inner()
         }).as_mut() {
-            if let Err(e) = output_handle.container.flush() {
-                error!("Failed to flush log file {:?}", e);
+            output_handle.flush();
+            if self.config.durability != config::Durability::Never {
+                if let Err(e) = output_handle.sync() {
+                    eprintln!("logpeek: failed to sync log file: {}", e);
+                }
             }
         };
 
+        for route in self.router.routes() {
+            if let RouteOutput::File(output) = &route.output {
+                let mut output = output.lock().unwrap_or_else(|err| err.into_inner());
+                output.flush();
+                if self.config.durability != config::Durability::Never {
+                    if let Err(err) = output.sync() {
+                        eprintln!("logpeek: failed to sync route log file: {}", err);
+                    }
+                }
+            }
+        }
+
         match self.config.console_mode {
             config::ConsoleMode::Stdout => stdout().flush().ok(),
             config::ConsoleMode::Stderr => stderr().flush().ok(),
@@ -188,13 +385,85 @@ impl Logger {
     fn split_output_file(&self) {
         // If two files are created within a second of each other, they will have the same name and logs will be appended to the first.
         if let Some(output_handle) = self.output_lock.lock().unwrap_or_else(|err| {
-            error!("A thread panicked while holding the log file lock, using into_inner {:?}", err);
+            eprintln!("logpeek: a thread panicked while holding the log file lock, using into_inner: {}", err);
             err.into_inner()
         }).as_mut() {
-            *output_handle = Logger::output_file_setup(&self.config);
+            // Reopening the same file would start a new encrypted segment in the middle of the current one
+            let log_path = Logger::get_log_pathbuf(&self.config);
+            if log_path == output_handle.path() {
+                return;
+            }
+
+            if self.config.durability != config::Durability::Never {
+                if let Err(e) = output_handle.sync() {
+                    eprintln!("logpeek: failed to sync log file before splitting it: {}", e);
+                }
+            }
+            if let Err(e) = output_handle.finish() {
+                eprintln!("logpeek: failed to finish the log file before splitting it: {}", e);
+            }
+            if let Err(e) = output_handle.write_index() {
+                eprintln!("logpeek: failed to write the index of the log file: {}", e);
+            }
+            match Output::try_open(&log_path, &self.config, self.config.file_format) {
+                Ok(output) => {
+                    let output = output.indexed(&self.config);
+                    #[cfg(feature = "hash-chain")]
+                    let output = output.continuing_chain(output_handle);
+                    *output_handle = output;
+                    stats::increment(&self.counters.rotations);
+                },
+                // Keep writing to the current file, an encrypted one gets a new segment when the failed write reopens it.
+                // Splitting is tried again with the next record.
+                Err(e) => eprintln!("logpeek: failed to create the next log file: {}", e),
+            }
         }
     }
 
+    /// Runs the record through the filters and writes it. Returns the written record, or `None` if it was filtered.
+    fn process(&self, record: &Record) -> Option<LogRecord> {
+        if !self.enabled(record.metadata()) {
+            return None;
+        }
+
+        if self.sampler.as_ref().is_some_and(|sampler| !sampler.keep(record)) {
+            return None;
+        }
+
+        let mut record = LogRecord::capture(record, self.get_current_time().ok());
+        if self.config.source_location == config::SourceLocation::RelativePath {
+            record.file = record.file.map(|file| shorten_path(&file).to_string());
+        }
+
+        if let Some(redactions) = &self.redactions {
+            redactions.apply(&mut record);
+        }
+
+        #[cfg(feature = "regex")]
+        if !self.message_filters.apply(&mut record, self.max_level) {
+            return None;
+        }
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            match rate_limiter.check(&record) {
+                RateDecision::Deny => return None,
+                RateDecision::Allow { summary: Some(summary) } => self.write_record(&summary),
+                RateDecision::Allow { summary: None } => {},
+            }
+        }
+
+        if let Some(duplicate_suppressor) = &self.duplicate_suppressor {
+            match duplicate_suppressor.check(&record) {
+                DuplicateDecision::Duplicate => return None,
+                DuplicateDecision::New(Some(summary)) => self.write_record(&summary),
+                DuplicateDecision::New(None) => {},
+            }
+        }
+
+        self.write_record(&record);
+        Some(record)
+    }
+
     /// Returns the current time as a string in the format specified in the config.
     pub fn get_current_time(&self) -> Result<String, ()> {
         let dt: OffsetDateTime = match self.config.timezone {
@@ -214,12 +483,17 @@ impl Logger {
         })
     }
 
-    /// Constructs the path for the log file.
-    fn get_log_pathbuf(config: &Config) -> PathBuf {
-        let mut out_path = match &config.out_dir_name {
+    /// Constructs the path for the log directory.
+    fn get_log_dir(config: &Config) -> PathBuf {
+        match &config.out_dir_name {
             OutputDirName::CurrentDir => PathBuf::from("."),
             OutputDirName::Custom(custom_dir) => PathBuf::from(custom_dir),
-        };
+        }
+    }
+
+    /// Constructs the path for the log file.
+    fn get_log_pathbuf(config: &Config) -> PathBuf {
+        let mut out_path = Logger::get_log_dir(config);
 
         out_path.push(match &config.out_file_name {
             config::OutputFileName::AutoGenerate => Logger::generate_log_name().unwrap_or_else(|_| String::from("default.log")),
@@ -231,9 +505,7 @@ impl Logger {
 
     /// Generates a log file name based on the current date and time (UTC).
     fn generate_log_name() -> Result<String, ()> {
-        let format = format_description::parse(
-            "[year]_[month]_[day]_[hour]_[minute]_[second].log",
-        ).map_err(|err| {
+        let format = format_description::parse_borrowed::<1>(LOG_NAME_FORMAT).map_err(|err| {
             error!("This shouldn't happen! Failed to parse date format: {:?}", err);
         })?;
 
@@ -243,22 +515,64 @@ impl Logger {
     }
 }
 
+/// Colors a message by its level, the way the console output does with `UseTermColor::True`.
+pub fn colorize(message: &str, level: log::Level) -> ColoredString {
+    match level {
+        log::Level::Error => message.red(),
+        log::Level::Warn => message.yellow(),
+        log::Level::Info => message.green(),
+        log::Level::Debug => message.blue(),
+        log::Level::Trace => message.magenta(),
+    }
+}
+
+/// The name of `OutputFileName::AutoGenerate` log files. `reader::log_files` relies on it to find the log files
+/// in a directory, and `query` to find the time range of a file.
+pub(crate) const LOG_NAME_FORMAT: &str = "[year]_[month]_[day]_[hour]_[minute]_[second].log";
+
+thread_local! {
+    static IN_LOGGER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the current thread as being inside `Log::log`. Records logged from there, e.g. by a `Display` impl
+/// or a redactor, would recurse or deadlock on the output lock, so they are written straight to stderr instead.
+struct ReentrancyGuard;
+
+impl ReentrancyGuard {
+    fn enter() -> Option<ReentrancyGuard> {
+        if IN_LOGGER.with(|in_logger| in_logger.replace(true)) {
+            None
+        } else {
+            Some(ReentrancyGuard)
+        }
+    }
+}
+
+impl Drop for ReentrancyGuard {
+    fn drop(&mut self) {
+        IN_LOGGER.with(|in_logger| in_logger.set(false));
+    }
+}
+
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.config.min_log_level && (self.config.target_filter.is_none() || !self.config.target_filter.as_ref().unwrap().contains(&metadata.target()))
+        metadata.level() <= self.max_level && self.target_filter.is_enabled(metadata.target())
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            let message = if self.get_current_time().is_ok() {
-                format!("{} {} {} - {}\n", self.get_current_time().unwrap(), record.level(), record.target(), record.args())
-            } else {
-                format!("{} {} - {}\n", record.level(), record.target(), record.args())
-            };
-
+        let _guard = match ReentrancyGuard::enter() {
+            Some(guard) => guard,
+            None => {
+                eprintln!("logpeek: {} {} - {}", record.level(), record.target(), record.args());
+                return;
+            },
+        };
 
-            self.write(&message, &record.level());
+        match self.process(record) {
+            Some(record) => self.counters.record(record.level, &record.target),
+            None => stats::increment(&self.counters.filtered),
         }
+        self.emit_stats();
     }
 
     fn flush(&self) {
@@ -267,7 +581,9 @@ impl Log for Logger {
 }
 
 #[cfg(test)]
+#[allow(clippy::get_first)]
 mod tests {
+    use std::fs::{self, File};
     use std::io::BufRead;
     use std::sync::Arc;
     use std::sync::atomic::{AtomicBool, Ordering};
@@ -442,4 +758,642 @@ mod tests {
 
         assert!(lines.get(0).unwrap().contains("LAST LOG BEFORE PANIC!"));
     }
-}
\ No newline at end of file
+
+    // Logs a record through `Log::log` with a custom line template and verifies the rendered line.
+    #[test]
+    fn test_custom_line_template() {
+        let log_file_name = String::from("test_custom_line_template.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            line_template: config::LineTemplate::Custom("[{level:<5}] {module}:{line} {kv.request_id} {message}"),
+            ..Default::default()
+        });
+
+        let key_values = [("request_id", "abc")];
+        logger.log(&Record::builder()
+            .args(format_args!("template test"))
+            .level(Level::Warn)
+            .target("app")
+            .module_path(Some("app::handler"))
+            .line(Some(7))
+            .key_values(&key_values)
+            .build());
+
+        let contents = fs::read_to_string(log_file_name).unwrap();
+
+        assert_eq!(contents, "[WARN ] app::handler:7 abc template test\n");
+    }
+
+    // Verifies that the file output can use a different format than the console output.
+    #[test]
+    fn test_logfmt_file_format() {
+        let log_file_name = String::from("test_logfmt_file_format.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            file_format: config::OutputFormat::Logfmt,
+            ..Default::default()
+        });
+
+        let key_values = [("tenant", "acme corp")];
+        logger.log(&Record::builder()
+            .args(format_args!("user logged in"))
+            .level(Level::Info)
+            .target("app")
+            .key_values(&key_values)
+            .build());
+
+        let contents = fs::read_to_string(log_file_name).unwrap();
+
+        assert!(contents.starts_with("ts="));
+        assert!(contents.ends_with(" level=info target=app msg=\"user logged in\" tenant=\"acme corp\"\n"));
+    }
+
+    // Verifies that records are sent to a GELF UDP endpoint in addition to the file output.
+    #[test]
+    fn test_gelf_udp_output() {
+        let log_file_name = String::from("test_gelf_udp_output.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            gelf_output: config::GelfOutput::Udp { address: receiver.local_addr().unwrap().to_string(), max_chunk_size: 8192 },
+            ..Default::default()
+        });
+
+        logger.log(&Record::builder()
+            .args(format_args!("gelf test"))
+            .level(Level::Error)
+            .target("app")
+            .file(Some("src/main.rs"))
+            .line(Some(3))
+            .build());
+
+        let mut buffer = [0u8; 8192];
+        let len = receiver.recv(&mut buffer).unwrap();
+        let payload = String::from_utf8_lossy(&buffer[..len]);
+
+        assert!(payload.contains("\"short_message\":\"gelf test\""));
+        assert!(payload.contains("\"level\":3"));
+        assert!(payload.contains("\"_target\":\"app\",\"_file\":\"src/main.rs\",\"_line\":3"));
+    }
+
+    // Verifies that records kept out of the default destinations by a route aren't sent to Graylog either.
+    #[test]
+    fn test_gelf_skips_only_routes() {
+        let log_file_name = String::from("test_gelf_skips_only_routes.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+        let _audit_cleaner = FileCleaner { file_name: String::from("test_gelf_skips_only_routes_audit.log") };
+
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            gelf_output: config::GelfOutput::Udp { address: receiver.local_addr().unwrap().to_string(), max_chunk_size: 8192 },
+            routes: vec![
+                config::Route { destination: config::RouteDestination::File(String::from("test_gelf_skips_only_routes_audit.log")), targets: vec!["audit"], min_level: LevelFilter::Debug, format: config::OutputFormat::PlainText, mode: config::RouteMode::Only },
+            ],
+            ..Default::default()
+        });
+
+        for (level, target, message) in [(Level::Warn, "audit", "audit only"), (Level::Debug, "app", "below min level"), (Level::Info, "app", "sent")] {
+            logger.log(&Record::builder().args(format_args!("{}", message)).level(level).target(target).build());
+        }
+
+        let mut buffer = [0u8; 8192];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..len]).contains("\"short_message\":\"sent\""));
+    }
+
+    // Verifies that the thread name is taken from the thread that emitted the record.
+    #[test]
+    fn test_thread_name_capture() {
+        let log_file_name = String::from("test_thread_name_capture.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = Arc::new(setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            thread_info: config::ThreadInfo::Name,
+            ..Default::default()
+        }));
+
+        let logger_clone = Arc::clone(&logger);
+        thread::Builder::new().name(String::from("worker-1")).spawn(move || {
+            logger_clone.log(&Record::builder()
+                .args(format_args!("from worker"))
+                .level(Level::Info)
+                .target("app")
+                .build());
+        }).unwrap().join().unwrap();
+
+        let contents = fs::read_to_string(log_file_name).unwrap();
+
+        assert!(contents.contains(" INFO app [worker-1] - from worker"));
+    }
+
+    // Verifies that context fields are merged into the record key-values.
+    #[test]
+    fn test_context_fields() {
+        let log_file_name = String::from("test_context_fields.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            file_format: config::OutputFormat::Logfmt,
+            ..Default::default()
+        });
+
+        let key_values = [("tenant", "override")];
+        crate::context::with([("request_id", "r1"), ("tenant", "acme")], || {
+            logger.log(&Record::builder()
+                .args(format_args!("handled"))
+                .level(Level::Info)
+                .target("app")
+                .key_values(&key_values)
+                .build());
+        });
+
+        let contents = fs::read_to_string(log_file_name).unwrap();
+
+        assert!(contents.ends_with(" msg=handled request_id=r1 tenant=override\n"));
+    }
+
+    // Verifies that a burst of identical records is written once followed by a summary line.
+    #[test]
+    fn test_suppress_duplicates() {
+        let log_file_name = String::from("test_suppress_duplicates.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            suppress_duplicates: config::SuppressDuplicates::True,
+            ..Default::default()
+        });
+
+        for message in ["disk full", "disk full", "disk full", "recovered"] {
+            logger.log(&Record::builder()
+                .args(format_args!("{}", message))
+                .level(Level::Error)
+                .target("app")
+                .build());
+        }
+        logger.flush();
+
+        let contents = fs::read_to_string(log_file_name).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(" - disk full"));
+        assert!(lines[1].ends_with(" - Previous message repeated 2 times"));
+        assert!(lines[2].ends_with(" - recovered"));
+    }
+
+    // Verifies that records dropped by the rate limit are reported on flush, even without a later allowed record.
+    #[test]
+    fn test_rate_limit_flush() {
+        let log_file_name = String::from("test_rate_limit_flush.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            rate_limit: config::RateLimit::PerCallsite { burst: 1, per_second: 0.001 },
+            ..Default::default()
+        });
+
+        for message in ["first", "second", "third"] {
+            logger.log(&Record::builder()
+                .args(format_args!("{}", message))
+                .level(Level::Warn)
+                .target("app")
+                .file(Some("src/main.rs"))
+                .line(Some(7))
+                .build());
+        }
+        logger.flush();
+        logger.flush();
+
+        let contents = fs::read_to_string(log_file_name).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" - first"));
+        assert!(lines[1].ends_with(" - 2 similar records were dropped by the rate limit"));
+    }
+
+    // Verifies that routes add destinations, replace the default ones and apply their own level and format.
+    #[test]
+    fn test_routes() {
+        let log_file_name = String::from("test_routes.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+        let _errors_cleaner = FileCleaner { file_name: String::from("test_routes_errors.log") };
+        let _audit_cleaner = FileCleaner { file_name: String::from("test_routes_audit.log") };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            routes: vec![
+                config::Route { destination: config::RouteDestination::File(String::from("test_routes_errors.log")), targets: vec![], min_level: LevelFilter::Error, format: config::OutputFormat::PlainText, mode: config::RouteMode::Also },
+                config::Route { destination: config::RouteDestination::File(String::from("test_routes_audit.log")), targets: vec!["audit"], min_level: LevelFilter::Debug, format: config::OutputFormat::Logfmt, mode: config::RouteMode::Only },
+            ],
+            ..Default::default()
+        });
+
+        assert_eq!(logger.max_level(), LevelFilter::Debug);
+
+        for (level, target, message) in [(Level::Info, "app", "started"), (Level::Error, "app", "failed"), (Level::Debug, "audit::login", "user logged in"), (Level::Debug, "app", "dropped")] {
+            logger.log(&Record::builder()
+                .args(format_args!("{}", message))
+                .level(level)
+                .target(target)
+                .build());
+        }
+
+        let main = fs::read_to_string(log_file_name).unwrap();
+        let errors = fs::read_to_string("test_routes_errors.log").unwrap();
+        let audit = fs::read_to_string("test_routes_audit.log").unwrap();
+
+        assert_eq!(main.lines().count(), 2);
+        assert!(main.contains(" - started") && main.contains(" - failed"));
+        assert_eq!(errors.lines().count(), 1);
+        assert!(errors.contains(" - failed"));
+        assert_eq!(audit.lines().count(), 1);
+        assert!(audit.ends_with(" level=debug target=audit::login msg=\"user logged in\"\n"));
+    }
+
+    // Verifies that a message containing a line break can't forge a second entry in the log file.
+    #[test]
+    fn test_log_injection_escaped() {
+        let log_file_name = String::from("test_log_injection_escaped.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            ..Default::default()
+        });
+
+        logger.log(&Record::builder()
+            .args(format_args!("login failed for {}", "bob\n2024-01-01T00:00:00Z INFO app - admin logged in"))
+            .level(Level::Warn)
+            .target("app")
+            .build());
+
+        let contents = fs::read_to_string(log_file_name).unwrap();
+
+        assert_eq!(contents.lines().count(), 1);
+        assert!(contents.ends_with(" - login failed for bob\\n2024-01-01T00:00:00Z INFO app - admin logged in\n"));
+    }
+
+    // Verifies that continuation lines of a multi-line message start with the marker and are read back into one entry.
+    #[test]
+    fn test_continuation_marker() {
+        let log_file_name = String::from("test_continuation_marker.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            file_escaping: config::Escaping::Marker("  | "),
+            ..Default::default()
+        });
+
+        logger.log(&Record::builder().args(format_args!("request failed\ncaused by: timeout")).level(Level::Error).target("app").build());
+        logger.log(&Record::builder().args(format_args!("done")).level(Level::Info).target("app").build());
+
+        let contents = fs::read_to_string(&log_file_name).unwrap();
+        assert!(contents.contains(" - request failed\n  | caused by: timeout\n"));
+
+        let reader = crate::reader::LogReader::new(config::OutputFormat::PlainText, &config::DateTimeFormat::ISO8601).continuation_marker("  | ");
+        let messages = reader.read_file(&log_file_name).unwrap().map(|entry| entry.unwrap().message).collect::<Vec<_>>();
+        assert_eq!(messages, ["request failed\ncaused by: timeout", "done"]);
+    }
+
+    // Verifies that an empty continuation marker is rejected.
+    #[test]
+    #[should_panic(expected = "Invalid continuation marker")]
+    fn test_empty_continuation_marker() {
+        setup(Config { console_escaping: config::Escaping::Marker(""), ..Default::default() });
+    }
+
+    // Verifies that secrets in key-values never reach the log file.
+    #[test]
+    fn test_redaction() {
+        let log_file_name = String::from("test_redaction.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            file_format: config::OutputFormat::Logfmt,
+            redaction: config::Redaction { keys: vec!["password"], ..Default::default() },
+            ..Default::default()
+        });
+
+        let key_values = [("user", "bob"), ("password", "hunter2")];
+        logger.log(&Record::builder()
+            .args(format_args!("login"))
+            .level(Level::Info)
+            .target("app")
+            .key_values(&key_values)
+            .build());
+
+        let contents = fs::read_to_string(log_file_name).unwrap();
+
+        assert!(!contents.contains("hunter2"));
+        assert!(contents.ends_with(" user=bob password=[REDACTED]\n"));
+    }
+
+    // Verifies that log files are created with the configured mode and that looser existing files are refused.
+    #[cfg(unix)]
+    #[test]
+    fn test_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let log_file_name = String::from("test_file_permissions.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+        let config = || Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            file_permissions: config::FilePermissions::Restrict {
+                dir_mode: 0o700,
+                file_mode: 0o600,
+                existing_files: config::LooserPermissions::Refuse,
+            },
+            ..Default::default()
+        };
+
+        drop(setup(config()));
+        assert_eq!(fs::metadata(&log_file_name).unwrap().permissions().mode() & 0o777, 0o600);
+
+        fs::set_permissions(&log_file_name, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(panic::catch_unwind(|| setup(config())).is_err());
+    }
+
+    // Verifies that a record logged while another record is being written doesn't reach the logger again.
+    #[test]
+    fn test_reentrant_logging() {
+        let log_file_name = String::from("test_reentrant_logging.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        struct Nested<'a>(&'a Logger);
+
+        impl std::fmt::Display for Nested<'_> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                self.0.log(&Record::builder().args(format_args!("inner")).level(Level::Error).target("app").build());
+                write!(f, "outer")
+            }
+        }
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            ..Default::default()
+        });
+
+        logger.log(&Record::builder().args(format_args!("{}", Nested(&logger))).level(Level::Info).target("app").build());
+
+        let contents = fs::read_to_string(log_file_name).unwrap();
+
+        assert_eq!(contents.lines().count(), 1);
+        assert!(contents.ends_with(" - outer\n"));
+    }
+
+    // Verifies that written and filtered records are counted and that the counters are logged when due.
+    #[test]
+    fn test_stats() {
+        let log_file_name = String::from("test_stats.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            target_filter: Some(vec!["noisy"]),
+            emit_stats: config::EmitStats::Every(std::time::Duration::ZERO),
+            recent_counts: config::RecentCounts::Window { window: std::time::Duration::from_secs(60), targets: vec!["app"] },
+            ..Default::default()
+        });
+
+        logger.log(&Record::builder().args(format_args!("kept")).level(Level::Error).target("app").build());
+        logger.log(&Record::builder().args(format_args!("filtered")).level(Level::Error).target("noisy").build());
+
+        let contents = fs::read_to_string(log_file_name).unwrap();
+        let stats = logger.stats();
+
+        assert_eq!(stats.records.error, 1);
+        assert_eq!(stats.records.total(), 1);
+        assert_eq!(stats.filtered, 1);
+        let recent = logger.counters.recent.as_ref().unwrap();
+        assert_eq!(recent.counts(std::time::Duration::from_secs(60), Some("app")).unwrap().error, 1);
+        assert_eq!(stats.file_bytes, contents.len() as u64);
+        assert!(contents.lines().last().unwrap().contains("logpeek::stats - Logger stats: records=1 errors=1 warnings=0 filtered=1"));
+    }
+
+    // Verifies that a reopened log file continues its hash chain.
+    #[cfg(feature = "hash-chain")]
+    #[test]
+    fn test_hash_chain() {
+        let log_file_name = String::from("test_hash_chain.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+        let config = || Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            hash_chain: config::HashChain::HmacSha256 { key: b"secret".to_vec() },
+            ..Default::default()
+        };
+
+        let logger = setup(config());
+        logger.write("first\n", &Level::Info);
+        logger.write("second\n", &Level::Info);
+        drop(logger);
+        setup(config()).write("third\n", &Level::Info);
+
+        let contents = fs::read_to_string(&log_file_name).unwrap();
+        assert_eq!(contents.lines().count(), 3);
+        assert!(contents.lines().all(|line| line.contains(" #sha256=")));
+        crate::hash_chain::Verifier::new(b"secret", config::OutputFormat::PlainText).verify_file(&log_file_name).unwrap();
+        assert!(crate::hash_chain::Verifier::new(b"guessed", config::OutputFormat::PlainText).verify_file(&log_file_name).is_err());
+    }
+
+    // Verifies that GELF records carry their hash as a field and can still be read back.
+    #[cfg(feature = "hash-chain")]
+    #[test]
+    fn test_hash_chain_gelf() {
+        let log_file_name = String::from("test_hash_chain_gelf.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            file_format: config::OutputFormat::Gelf,
+            hash_chain: config::HashChain::HmacSha256 { key: b"secret".to_vec() },
+            ..Default::default()
+        });
+
+        logger.log(&Record::builder().args(format_args!("hashed")).level(Level::Info).target("app").build());
+        drop(logger);
+
+        let contents = fs::read_to_string(&log_file_name).unwrap();
+        assert!(contents.contains(",\"_sha256\":\"") && contents.ends_with("\"}\n"));
+        crate::hash_chain::Verifier::new(b"secret", config::OutputFormat::Gelf).verify_file(&log_file_name).unwrap();
+
+        let entry = crate::reader::LogReader::new(config::OutputFormat::Gelf, &config::DateTimeFormat::ISO8601).parse_line(contents.trim_end()).unwrap();
+        assert_eq!(entry.message, "hashed");
+        assert!(entry.key_values.is_empty());
+    }
+
+    // Verifies that an encrypted log file doesn't contain the records in plain text and can be decrypted.
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption() {
+        let log_file_name = String::from("test_encryption.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            encryption: config::Encryption::ChaCha20Poly1305 { key_id: String::from("test"), key: [3; 32] },
+            ..Default::default()
+        });
+
+        logger.log(&Record::builder().args(format_args!("card 4111")).level(Level::Info).target("app").build());
+
+        assert!(!String::from_utf8_lossy(&fs::read(&log_file_name).unwrap()).contains("card 4111"));
+        let mut contents = String::new();
+        io::Read::read_to_string(&mut crate::encryption::DecryptingReader::open(&log_file_name, [("test", [3; 32])]).unwrap(), &mut contents).unwrap();
+        assert!(contents.ends_with(" INFO app - card 4111\n"));
+    }
+
+    // Verifies that splitting an encrypted log file onto the path it already has keeps the file readable.
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption_split_same_path() {
+        let log_file_name = String::from("test_encryption_split_same_path.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            split_log_files: config::SplitLogFiles::True(1),
+            encryption: config::Encryption::ChaCha20Poly1305 { key_id: String::from("test"), key: [3; 32] },
+            ..Default::default()
+        });
+
+        for message in ["first", "second", "third"] {
+            logger.log(&Record::builder().args(format_args!("{}", message)).level(Level::Info).target("app").build());
+        }
+        drop(logger);
+
+        let mut reader = crate::encryption::DecryptingReader::open(&log_file_name, [("test", [3; 32])]).unwrap();
+        let mut contents = String::new();
+        io::Read::read_to_string(&mut reader, &mut contents).unwrap();
+        assert_eq!(contents.lines().count(), 3);
+        assert!(contents.ends_with(" INFO app - third\n"));
+        assert_eq!(reader.incomplete_segments(), 0);
+    }
+
+    // Verifies that the reader parses the files the logger writes, in every file format.
+    #[test]
+    fn test_reader_round_trip() {
+        for (index, format) in [config::OutputFormat::PlainText, config::OutputFormat::Logfmt, config::OutputFormat::Gelf].into_iter().enumerate() {
+            let log_file_name = format!("test_reader_round_trip_{}.log", index);
+            let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+            let logger = setup(Config {
+                out_file_name: OutputFileName::Custom(log_file_name.clone()),
+                out_dir_name: OutputDirName::CurrentDir,
+                logging_mode: LoggingMode::File,
+                source_location: config::SourceLocation::FullPath,
+                show_module_path: config::ShowModulePath::True,
+                thread_info: config::ThreadInfo::NameAndId,
+                show_process_id: config::ShowProcessId::True,
+                file_format: format,
+                file_escaping: config::Escaping::Indent,
+                ..Default::default()
+            });
+
+            logger.log(&Record::builder().args(format_args!("first - line\nsecond line")).level(Level::Warn).target("app::db")
+                .module_path(Some("app::db::pool")).file(Some("src/db/pool.rs")).line(Some(42)).build());
+            logger.log(&Record::builder().args(format_args!("done")).level(Level::Info).target("app").build());
+
+            let reader = crate::reader::LogReader::new(format, &config::DateTimeFormat::ISO8601).escaping(&config::Escaping::Indent);
+            let entries = reader.read_file(&log_file_name).unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+
+            assert_eq!(entries.len(), 2);
+            let entry = &entries[0];
+            assert_eq!((entry.level, entry.target.as_str()), (Level::Warn, "app::db"));
+            assert_eq!(entry.message, "first - line\nsecond line");
+            assert_eq!(entry.module_path.as_deref(), Some("app::db::pool"));
+            assert_eq!((entry.file.as_deref(), entry.line), (Some("src/db/pool.rs"), Some(42)));
+            assert_eq!(entry.process_id, Some(std::process::id()));
+            assert!(entry.thread_id.is_some());
+            assert!(entry.timestamp.is_some());
+            assert_eq!(entries[1].message, "done");
+        }
+    }
+
+    // Verifies that the reader restores what was logged with the default escaping, for records with and without a file.
+    #[test]
+    fn test_reader_round_trip_escaped() {
+        let log_file_name = String::from("test_reader_round_trip_escaped.log");
+        let _file_cleaner = FileCleaner { file_name: log_file_name.clone() };
+
+        let logger = setup(Config {
+            out_file_name: OutputFileName::Custom(log_file_name.clone()),
+            out_dir_name: OutputDirName::CurrentDir,
+            logging_mode: LoggingMode::File,
+            source_location: config::SourceLocation::FullPath,
+            thread_info: config::ThreadInfo::NameAndId,
+            show_process_id: config::ShowProcessId::True,
+            ..Default::default()
+        });
+
+        logger.log(&Record::builder().args(format_args!("first - line\nC:\\logs \\n")).level(Level::Warn).target("app::db")
+            .file(Some("src\\db.rs")).line(Some(42)).build());
+        logger.log(&Record::builder().args(format_args!("no file\r\n\tindented")).level(Level::Info).target("app").build());
+
+        let reader = crate::reader::LogReader::new(config::OutputFormat::PlainText, &config::DateTimeFormat::ISO8601);
+        let entries = reader.read_file(&log_file_name).unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].message, "first - line\nC:\\logs \\n");
+        assert_eq!((entries[0].file.as_deref(), entries[0].line), (Some("src\\db.rs"), Some(42)));
+        assert_eq!((entries[1].level, entries[1].target.as_str()), (Level::Info, "app"));
+        assert_eq!(entries[1].message, "no file\r\n\tindented");
+        assert_eq!((entries[1].file.as_deref(), entries[1].line), (None, None));
+        assert!(entries[1].thread_id.is_some());
+        assert_eq!(entries[1].process_id, Some(std::process::id()));
+    }
+}
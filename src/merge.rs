@@ -0,0 +1,176 @@
+//! Merges the entries of several log files into one timeline, see `merge`.
+//!
+//! # Examples
+//! ```no_run
+//! use time::UtcOffset;
+//! use logpeek::config::{DateTimeFormat, OutputFormat};
+//! use logpeek::reader::LogReader;
+//!
+//! let api = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::ISO8601);
+//! // Written with `TimeZone::Local` in UTC+2 and a format without the offset
+//! let worker = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::Custom("[year]-[month]-[day] [hour]:[minute]:[second]"))
+//!     .assume_offset(UtcOffset::from_hms(2, 0, 0).unwrap());
+//!
+//! let sources = ["api/logs", "worker/logs"];
+//! let inputs = vec![api.read_path(sources[0]).unwrap(), worker.read_path(sources[1]).unwrap()];
+//! for merged in logpeek::merge::merge(inputs) {
+//!     let merged = merged.unwrap();
+//!     println!("[{}] {} {}", sources[merged.source], merged.entry.level, merged.entry.message);
+//! }
+//! ```
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::io;
+
+use time::OffsetDateTime;
+
+use crate::reader::Entry;
+
+/// An entry returned by `merge`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergedEntry {
+    /// The index of the input the entry came from.
+    pub source: usize,
+    pub entry: Entry,
+}
+
+/// Merges the entries of the inputs, e.g. `LogReader::read_path` for each file or directory, by their timestamps.
+///
+/// Timestamps are compared as instants, so inputs written in different time zones are interleaved correctly as
+/// long as their timestamps have an offset, or the `LogReader` was told the offset with `assume_offset`. The
+/// entries of each input have to be in order already, which is how logpeek writes them. An entry without a
+/// parsed timestamp stays right after the entry before it in its input. Entries with the same timestamp are
+/// returned in the order of their inputs.
+///
+/// Only one entry per input is held in memory, so this works for any number and size of files.
+pub fn merge<I: Iterator<Item = io::Result<Entry>>>(inputs: Vec<I>) -> Merge<I> {
+    Merge {
+        refill: (0..inputs.len()).rev().collect(),
+        last_timestamps: vec![None; inputs.len()],
+        inputs,
+        heads: BinaryHeap::new(),
+    }
+}
+
+/// The next entry of an input, ordered by its timestamp and then by its input.
+struct Head {
+    timestamp: Option<OffsetDateTime>,
+    source: usize,
+    entry: Entry,
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.timestamp, self.source).cmp(&(other.timestamp, other.source))
+    }
+}
+
+/// An iterator over the merged entries, see `merge`.
+pub struct Merge<I> {
+    inputs: Vec<I>,
+    /// The timestamp of the last entry of each input that had one, used for the entries that don't.
+    last_timestamps: Vec<Option<OffsetDateTime>>,
+    heads: BinaryHeap<Reverse<Head>>,
+    /// Inputs whose next entry has to be read before the earliest head is known.
+    refill: Vec<usize>,
+}
+
+impl<I: Iterator<Item = io::Result<Entry>>> Iterator for Merge<I> {
+    type Item = io::Result<MergedEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(&source) = self.refill.last() {
+            match self.inputs[source].next() {
+                Some(Ok(entry)) => {
+                    if entry.timestamp.is_some() {
+                        self.last_timestamps[source] = entry.timestamp;
+                    }
+                    self.heads.push(Reverse(Head { timestamp: self.last_timestamps[source], source, entry }));
+                    self.refill.pop();
+                },
+                // The input is refilled again on the next call
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    self.refill.pop();
+                },
+            }
+        }
+
+        let Reverse(head) = self.heads.pop()?;
+        self.refill.push(head.source);
+        Some(Ok(MergedEntry { source: head.source, entry: head.entry }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::UtcOffset;
+    use crate::config::{DateTimeFormat, OutputFormat};
+    use crate::reader::LogReader;
+    use super::*;
+
+    // Verifies that entries from different time zones and formats are interleaved by the instant they were logged.
+    #[test]
+    fn test_merge_time_zones() {
+        let utc = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::RFC3339);
+        let local = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::Custom("[hour]:[minute]:[second] [day].[month].[year]"))
+            .assume_offset(UtcOffset::from_hms(2, 0, 0).unwrap());
+        let gelf = LogReader::new(OutputFormat::Gelf, &DateTimeFormat::ISO8601);
+
+        let utc_file = concat!(
+            "2024-03-01T10:00:00Z INFO api - a\n",
+            "2024-03-01T10:00:02+00:00 INFO api - c\n",
+            "2024-03-01T12:00:05+02:00 INFO api - f\n",
+        );
+        let local_file = concat!(
+            "12:00:01 01.03.2024 INFO worker - b\n",
+            "    continued\n",
+            "12:00:04 01.03.2024 INFO worker - e\n",
+        );
+        // 2024-03-01T10:00:03Z
+        let gelf_file = "{\"short_message\":\"d\",\"timestamp\":1709287203.000,\"level\":6,\"_target\":\"gelf\"}\n";
+
+        let merged = merge(vec![utc.entries(utc_file.as_bytes()), local.entries(local_file.as_bytes()), gelf.entries(gelf_file.as_bytes())])
+            .map(|merged| merged.map(|merged| (merged.source, merged.entry.message)))
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(merged, [
+            (0, String::from("a")),
+            (1, String::from("b\ncontinued")),
+            (0, String::from("c")),
+            (2, String::from("d")),
+            (1, String::from("e")),
+            (0, String::from("f")),
+        ]);
+    }
+
+    // Verifies that entries without a timestamp stay behind the entry before them and ties keep the input order.
+    #[test]
+    fn test_merge_without_timestamps() {
+        let reader = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::Custom("[hour]:[minute]:[second]"));
+
+        let first = "10:00:00 INFO a - 1\n10:00:02 INFO a - 2\n";
+        let second = "10:00:01 INFO b - 3\n";
+
+        let merged = merge(vec![reader.entries(first.as_bytes()), reader.entries(second.as_bytes())])
+            .map(|merged| merged.unwrap().entry.message)
+            .collect::<Vec<_>>();
+
+        assert_eq!(merged, ["1", "2", "3"]);
+    }
+}
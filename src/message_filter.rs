@@ -0,0 +1,84 @@
+//! Regex based message filters, see `config::MessageFilter`.
+use log::LevelFilter;
+use regex::Regex;
+
+use crate::config::{MessageFilter, MessageFilterAction};
+use crate::filter::TargetMatcher;
+use crate::record::LogRecord;
+
+struct CompiledMessageFilter {
+    regex: Regex,
+    target: Option<TargetMatcher>,
+    action: MessageFilterAction,
+}
+
+/// The message filters from the config, compiled once in `Logger::new`.
+pub(crate) struct MessageFilters {
+    filters: Vec<CompiledMessageFilter>,
+}
+
+impl MessageFilters {
+    pub fn new(filters: &[MessageFilter]) -> Result<MessageFilters, regex::Error> {
+        let filters = filters.iter().map(|filter| {
+            Ok(CompiledMessageFilter {
+                regex: Regex::new(filter.pattern)?,
+                target: filter.target.map(|target| TargetMatcher::new([target])),
+                action: filter.action,
+            })
+        }).collect::<Result<Vec<_>, regex::Error>>()?;
+
+        Ok(MessageFilters { filters })
+    }
+
+    /// Applies the first filter matching the record. Returns `false` if the record should be dropped.
+    pub fn apply(&self, record: &mut LogRecord, min_log_level: LevelFilter) -> bool {
+        let matching_filter = self.filters.iter().find(|filter| {
+            filter.target.as_ref().is_none_or(|target| target.matches(&record.target)) && filter.regex.is_match(&record.message)
+        });
+
+        match matching_filter.map(|filter| filter.action) {
+            None => true,
+            Some(MessageFilterAction::Drop) => false,
+            Some(MessageFilterAction::Downgrade(level)) => {
+                // Never raise the level of a record that is already less severe
+                record.level = record.level.max(level);
+                record.level <= min_log_level
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use super::*;
+
+    // Verifies dropping, target scoping and downgrading of matching records.
+    #[test]
+    fn test_message_filters() {
+        let filters = MessageFilters::new(&[
+            MessageFilter { pattern: "^connection reset", target: Some("hyper"), action: MessageFilterAction::Drop },
+            MessageFilter { pattern: "retrying in \\d+s", target: None, action: MessageFilterAction::Downgrade(Level::Info) },
+            MessageFilter { pattern: "heartbeat", target: None, action: MessageFilterAction::Downgrade(Level::Trace) },
+        ]).unwrap();
+
+        assert!(!filters.apply(&mut LogRecord::test("hyper::proto", "connection reset by peer"), LevelFilter::Info));
+        assert!(filters.apply(&mut LogRecord::test("app", "connection reset by peer"), LevelFilter::Info));
+
+        let mut downgraded = LogRecord { level: Level::Error, ..LogRecord::test("app", "retrying in 5s") };
+        assert!(filters.apply(&mut downgraded, LevelFilter::Info));
+        assert_eq!(downgraded.level, Level::Info);
+
+        assert!(!filters.apply(&mut LogRecord::test("app", "heartbeat"), LevelFilter::Info));
+
+        let mut debug = LogRecord { level: Level::Debug, ..LogRecord::test("app", "retrying in 5s") };
+        assert!(!filters.apply(&mut debug, LevelFilter::Info));
+        assert_eq!(debug.level, Level::Debug);
+    }
+
+    // Verifies that invalid patterns are reported.
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(MessageFilters::new(&[MessageFilter { pattern: "(", target: None, action: MessageFilterAction::Drop }]).is_err());
+    }
+}
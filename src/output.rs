@@ -0,0 +1,585 @@
+//! Log file outputs, shared by the main log file and the files of routing rules.
+use std::{fs, io};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::{Config, config};
+use crate::query::Index;
+
+pub(crate) enum OutputContainer {
+    File(File),
+    Buffered(BufWriter<File>),
+    #[cfg(feature = "encryption")]
+    Encrypted(Box<crate::encryption::EncryptingWriter<OutputContainer>>),
+}
+
+/// How long to wait before the first attempt to reopen a file that failed, doubled after every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The hash chain state of a file, empty without the `hash-chain` feature.
+#[derive(Clone, Copy, Default)]
+struct Chain {
+    /// The hash of the last record written to the file, if the hash chain is enabled.
+    #[cfg(feature = "hash-chain")]
+    last_hash: Option<crate::hash_chain::Hash>,
+}
+
+impl Chain {
+    /// Starts the chain of the file at `log_path`, continuing from its last hash if it already has records.
+    #[cfg(feature = "hash-chain")]
+    fn open(log_path: &Path, config: &Config, format: config::OutputFormat) -> io::Result<Chain> {
+        Ok(Chain {
+            last_hash: match config.hash_chain {
+                config::HashChain::HmacSha256 { .. } => Some(crate::hash_chain::last_hash(log_path, format)?.unwrap_or([0; 32])),
+                config::HashChain::Disabled => None,
+            },
+        })
+    }
+
+    #[cfg(not(feature = "hash-chain"))]
+    fn open(_log_path: &Path, _config: &Config, _format: config::OutputFormat) -> io::Result<Chain> {
+        Ok(Chain::default())
+    }
+
+    /// Writes the message, rendered in `format`, with its hash added if the hash chain is enabled. The chain only
+    /// advances if the write succeeded. Returns the number of bytes written.
+    fn write(&mut self, container: &mut OutputContainer, message: &str, config: &Config, format: config::OutputFormat) -> io::Result<usize> {
+        #[cfg(feature = "hash-chain")]
+        if let (Some(previous), config::HashChain::HmacSha256 { key }) = (&mut self.last_hash, &config.hash_chain) {
+            let (line, hash) = crate::hash_chain::chain_line(key, previous, message, format);
+            container.write_all(line.as_bytes())?;
+            *previous = hash;
+            return Ok(line.len());
+        }
+        #[cfg(not(feature = "hash-chain"))]
+        let _ = (config, format);
+
+        container.write_all(message.as_bytes())?;
+        Ok(message.len())
+    }
+}
+
+pub(crate) struct Output {
+    pub container: OutputContainer,
+    pub file_size: u64,
+    path: PathBuf,
+    /// The format of the records in the file, which decides how their hashes are added.
+    format: config::OutputFormat,
+    unsynced_records: u32,
+    /// The records waiting in the buffer of an `Asynchronous` file.
+    buffered_records: u64,
+    last_sync: Instant,
+    failure: Option<WriteFailure>,
+    chain: Chain,
+    index: Option<Index>,
+}
+
+/// The state of a file that can't be written, see `config::WriteFailurePolicy`.
+struct WriteFailure {
+    /// Records that didn't make it into the file and weren't buffered.
+    missing: u64,
+    /// Records that were still in the buffer of the file when flushing it failed.
+    lost: u64,
+    buffered: VecDeque<String>,
+    retry_at: Instant,
+    backoff: Duration,
+}
+
+impl OutputContainer {
+    /// Writes out the buffer, if any, and waits until the file contents have reached the disk.
+    fn sync_data(&mut self) -> io::Result<()> {
+        match self {
+            OutputContainer::File(file) => file.sync_data(),
+            OutputContainer::Buffered(buffer) => {
+                buffer.flush()?;
+                buffer.get_ref().sync_data()
+            },
+            #[cfg(feature = "encryption")]
+            OutputContainer::Encrypted(writer) => writer.get_mut().sync_data(),
+        }
+    }
+
+    /// The number of bytes waiting in the buffer of an `Asynchronous` file.
+    fn buffered_len(&mut self) -> usize {
+        match self {
+            OutputContainer::File(_) => 0,
+            OutputContainer::Buffered(buffer) => buffer.buffer().len(),
+            #[cfg(feature = "encryption")]
+            OutputContainer::Encrypted(writer) => writer.get_mut().buffered_len(),
+        }
+    }
+
+    /// The number of bytes that writing a record of `len` bytes adds to the file.
+    fn stored_len(&self, len: usize) -> usize {
+        match self {
+            #[cfg(feature = "encryption")]
+            OutputContainer::Encrypted(_) => crate::encryption::CHUNK_OVERHEAD + len,
+            _ => len,
+        }
+    }
+
+    /// Writes a record with the hash chain of `chain` and keeps count of the records waiting in the buffer.
+    fn write_record(&mut self, chain: &mut Chain, buffered_records: &mut u64, message: &str, config: &Config, format: config::OutputFormat) -> io::Result<usize> {
+        let buffered_before = self.buffered_len();
+        let written = chain.write(self, message, config, format)?;
+
+        // The buffer only grows by the record if it wasn't written out to make room for it
+        let buffered = self.buffered_len();
+        *buffered_records = if buffered == 0 {
+            0
+        } else if buffered == buffered_before + self.stored_len(written) {
+            *buffered_records + 1
+        } else {
+            1
+        };
+        Ok(written)
+    }
+}
+
+impl Write for OutputContainer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputContainer::File(file) => file.write(buf),
+            OutputContainer::Buffered(buffer) => buffer.write(buf),
+            #[cfg(feature = "encryption")]
+            OutputContainer::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputContainer::File(file) => file.flush(),
+            OutputContainer::Buffered(buffer) => buffer.flush(),
+            #[cfg(feature = "encryption")]
+            OutputContainer::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+impl Output {
+    /// Opens the log file at `log_path` for appending, creating it and its directory if needed.
+    ///
+    /// # Panics
+    /// This function will panic if it fails to create the log directory or the log file, or if the file has
+    /// looser permissions than `FilePermissions::Restrict` allows and `LooserPermissions::Refuse` is set.
+    pub fn open(log_path: &Path, config: &Config, format: config::OutputFormat) -> Output {
+        Output::try_open(log_path, config, format).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `open`, but returns the error instead of panicking.
+    pub fn try_open(log_path: &Path, config: &Config, format: config::OutputFormat) -> io::Result<Output> {
+        let mut dir_builder = fs::DirBuilder::new();
+        let mut options = File::options();
+        dir_builder.recursive(true);
+        options.append(true).create(true);
+
+        #[cfg(unix)]
+        if let config::FilePermissions::Restrict { dir_mode, file_mode, existing_files } = &config.file_permissions {
+            use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+            dir_builder.mode(*dir_mode);
+            options.mode(*file_mode);
+            check_existing_permissions(log_path, *file_mode, existing_files)?;
+        }
+
+        let log_dir = log_path.parent().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Failed to get parent directory for log path: {:?}", log_path))
+        })?;
+        dir_builder.create(log_dir).map_err(|err| {
+            io::Error::new(err.kind(), format!("Failed to create log directory at {:?}: {}", log_path, err))
+        })?;
+
+        let file = options.open(log_path).map_err(|err| {
+            io::Error::new(err.kind(), format!("Failed to open log file at {:?}: {}", log_path, err))
+        })?;
+
+        let output_container = if config.logging_strategy == config::LoggingStrategy::Asynchronous {
+            OutputContainer::Buffered(BufWriter::new(file))
+        } else {
+            OutputContainer::File(file)
+        };
+
+        #[cfg(feature = "encryption")]
+        let output_container = match &config.encryption {
+            config::Encryption::Disabled => output_container,
+            config::Encryption::ChaCha20Poly1305 { key_id, key } => OutputContainer::Encrypted(
+                Box::new(crate::encryption::EncryptingWriter::new(output_container, key_id, key)?)
+            ),
+        };
+
+        let chain = Chain::open(log_path, config, format)?;
+
+        Ok(Output {
+            container: output_container,
+            file_size: 0,
+            path: log_path.to_path_buf(),
+            format,
+            unsynced_records: 0,
+            buffered_records: 0,
+            last_sync: Instant::now(),
+            failure: None,
+            chain,
+            index: None,
+        })
+    }
+
+    /// Writes a rendered record. While the file can't be written, records are handled as `config.write_failure`
+    /// says and reopening the file is retried with an exponential backoff. `render_notice` renders the
+    /// message about missing records that is written once the file works again.
+    ///
+    /// Returns the number of bytes written to the file, or `None` if the record went to the fallback.
+    pub fn write_message(&mut self, message: &str, level: log::Level, config: &Config, render_notice: &dyn Fn(String) -> String) -> Option<usize> {
+        let mut recovered = 0;
+        if self.failure.is_some() {
+            match self.recover(config, render_notice) {
+                Some(written) => recovered = written,
+                None => {
+                    self.fall_back(message, &config.write_failure);
+                    return None;
+                },
+            }
+        }
+
+        let written = match self.container.write_record(&mut self.chain, &mut self.buffered_records, message, config, self.format) {
+            Ok(written) => written,
+            Err(err) => {
+                eprintln!("logpeek: failed to write to log file at {:?}, will retry: {}", self.path, err);
+                self.start_failure();
+                self.fall_back(message, &config.write_failure);
+                return None;
+            },
+        };
+
+        self.file_size += written as u64;
+        if let Some(index) = &mut self.index {
+            index.record(recovered as u64, written as u64);
+        }
+        if let Err(err) = self.record_written(level, &config.durability) {
+            eprintln!("logpeek: failed to sync log file at {:?}: {}", self.path, err);
+        }
+        Some(recovered + written)
+    }
+
+    /// Writes out the buffer of the file, if it has one. A failure is handled like a failed write: the following
+    /// records go where `config.write_failure` says until reopening the file works.
+    pub fn flush(&mut self) {
+        // Records go to the fallback until the file was reopened, there's nothing to flush
+        if self.failure.is_some() {
+            return;
+        }
+
+        match self.container.flush() {
+            Ok(()) => self.buffered_records = 0,
+            Err(err) => {
+                eprintln!("logpeek: failed to flush log file at {:?}, will retry: {}", self.path, err);
+                self.start_failure();
+            },
+        }
+    }
+
+    fn start_failure(&mut self) {
+        self.failure = Some(WriteFailure {
+            missing: 0,
+            lost: std::mem::take(&mut self.buffered_records),
+            buffered: VecDeque::new(),
+            retry_at: Instant::now() + INITIAL_BACKOFF,
+            backoff: INITIAL_BACKOFF,
+        });
+    }
+
+    fn fall_back(&mut self, message: &str, policy: &config::WriteFailurePolicy) {
+        let Some(failure) = self.failure.as_mut() else { return };
+
+        match policy {
+            config::WriteFailurePolicy::Stderr => {
+                eprint!("{}", message);
+                failure.missing += 1;
+            },
+            config::WriteFailurePolicy::Memory(capacity) => {
+                if failure.buffered.len() >= *capacity {
+                    failure.missing += 1;
+                    if failure.buffered.pop_front().is_none() {
+                        return;
+                    }
+                }
+                failure.buffered.push_back(message.to_string());
+            },
+            config::WriteFailurePolicy::Drop => failure.missing += 1,
+        }
+    }
+
+    /// Reopens the file if the backoff has passed, then writes the notice and the buffered records.
+    /// Returns the number of bytes written if the file can be written again.
+    fn recover(&mut self, config: &Config, render_notice: &dyn Fn(String) -> String) -> Option<usize> {
+        let Some(failure) = self.failure.as_mut() else { return Some(0) };
+        if Instant::now() < failure.retry_at {
+            return None;
+        }
+
+        let mut written = 0;
+        let mut chain = self.chain;
+        let reopened = Output::try_open(&self.path, config, self.format).and_then(|mut output| {
+            if let Some(notice) = failure.notice(&config.write_failure) {
+                written += output.container.write_record(&mut chain, &mut output.buffered_records, &render_notice(notice), config, self.format)?;
+            }
+            for message in &failure.buffered {
+                written += output.container.write_record(&mut chain, &mut output.buffered_records, message, config, self.format)?;
+            }
+            Ok(output)
+        });
+
+        match reopened {
+            Ok(output) => {
+                self.container = output.container;
+                self.buffered_records = output.buffered_records;
+                self.chain = chain;
+                self.file_size += written as u64;
+                self.failure = None;
+                Some(written)
+            },
+            Err(_) => {
+                failure.backoff = (failure.backoff * 2).min(MAX_BACKOFF);
+                failure.retry_at = Instant::now() + failure.backoff;
+                None
+            },
+        }
+    }
+
+    /// Ends the file before it is replaced by the next one after a rotation: writes out its buffer and, if it is
+    /// encrypted, the final chunk of its segment.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match &mut self.container {
+            #[cfg(feature = "encryption")]
+            OutputContainer::Encrypted(writer) => writer.finish(),
+            container => container.flush(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Starts collecting checkpoints for the index of the file if `config.log_index` asks for it.
+    /// Only the main log file is indexed, since it's the only one that is rotated.
+    pub fn indexed(mut self, config: &Config) -> Output {
+        #[cfg(feature = "encryption")]
+        if !matches!(config.encryption, config::Encryption::Disabled) {
+            return self;
+        }
+
+        if let config::LogIndex::Every(every) = config.log_index {
+            // The file may have been appended to
+            let position = fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0);
+            self.index = Some(Index::new(every, position));
+        }
+        self
+    }
+
+    /// Writes the index of the file, if it has one. Called when the file is rotated.
+    pub fn write_index(&self) -> io::Result<()> {
+        match &self.index {
+            Some(index) => index.write(&self.path),
+            None => Ok(()),
+        }
+    }
+
+    /// Continues the hash chain of the file this one replaces after a rotation.
+    #[cfg(feature = "hash-chain")]
+    pub fn continuing_chain(mut self, previous: &Output) -> Output {
+        if self.chain.last_hash.is_some() {
+            self.chain = previous.chain;
+        }
+        self
+    }
+
+    /// Syncs the file to disk if `durability` asks for it after a record of `level` was written.
+    pub fn record_written(&mut self, level: log::Level, durability: &config::Durability) -> io::Result<()> {
+        self.unsynced_records = self.unsynced_records.saturating_add(1);
+
+        let sync = match durability {
+            config::Durability::Never => false,
+            config::Durability::EveryRecords(n) => self.unsynced_records >= *n,
+            config::Durability::OnWriteAfterMillis(millis) => self.last_sync.elapsed().as_millis() >= u128::from(*millis),
+            config::Durability::OnError => level == log::Level::Error,
+        };
+
+        if sync { self.sync() } else { Ok(()) }
+    }
+
+    /// Syncs the file to disk if any records were written since the last sync.
+    pub fn sync(&mut self) -> io::Result<()> {
+        // A file that can't be written is synced once it was reopened
+        if self.unsynced_records == 0 || self.failure.is_some() {
+            return Ok(());
+        }
+        self.container.sync_data()?;
+        self.unsynced_records = 0;
+        self.buffered_records = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+impl WriteFailure {
+    /// The message about the records that are missing from the file, if there are any.
+    fn notice(&self, policy: &config::WriteFailurePolicy) -> Option<String> {
+        let records = |count: u64| match count {
+            1 => String::from("1 record was"),
+            _ => format!("{} records were", count),
+        };
+        let (to_stderr, lost) = match policy {
+            config::WriteFailurePolicy::Stderr => (self.missing, self.lost),
+            _ => (0, self.missing + self.lost),
+        };
+
+        match (to_stderr, lost) {
+            (0, 0) => None,
+            (0, lost) => Some(format!("{} lost because this log file could not be written", records(lost))),
+            (to_stderr, 0) => Some(format!("{} written to stderr because this log file could not be written", records(to_stderr))),
+            (to_stderr, lost) => Some(format!(
+                "{} written to stderr and {} lost because this log file could not be written", records(to_stderr), records(lost)
+            )),
+        }
+    }
+}
+
+/// Compares the permissions of an already existing log file to `file_mode`. Files that don't exist yet are skipped.
+#[cfg(unix)]
+fn check_existing_permissions(log_path: &Path, file_mode: u32, existing_files: &config::LooserPermissions) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if *existing_files == config::LooserPermissions::Ignore {
+        return Ok(());
+    }
+    let mode = match fs::metadata(log_path) {
+        Ok(metadata) => metadata.permissions().mode() & 0o777,
+        Err(_) => return Ok(()),
+    };
+    if mode & !file_mode == 0 {
+        return Ok(());
+    }
+
+    match existing_files {
+        config::LooserPermissions::Refuse => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Log file at {:?} has permissions {:o}, which are looser than {:o}", log_path, mode, file_mode),
+        )),
+        _ => {
+            eprintln!("logpeek: log file at {:?} has permissions {:o}, which are looser than {:o}", log_path, mode, file_mode);
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use super::*;
+
+    // Cleans up the log file after the test is done.
+    struct FileCleaner {
+        file_name: &'static str,
+    }
+
+    impl Drop for FileCleaner {
+        fn drop(&mut self) {
+            fs::remove_file(self.file_name).unwrap();
+        }
+    }
+
+    // Verifies that a failed flush of the buffer counts the buffered records as lost and sends the following records to the fallback.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_flush_failure() {
+        let config = Config {
+            logging_strategy: config::LoggingStrategy::Asynchronous,
+            write_failure: config::WriteFailurePolicy::Memory(10),
+            ..Default::default()
+        };
+        // Every write to /dev/full fails with ENOSPC
+        let mut output = Output::open(Path::new("/dev/full"), &config, config::OutputFormat::PlainText);
+        let render_notice = |notice: String| format!("{}\n", notice);
+
+        assert_eq!(output.write_message("buffered\n", Level::Info, &config, &render_notice), Some(9));
+        assert_eq!(output.write_message("also buffered\n", Level::Info, &config, &render_notice), Some(14));
+        assert_eq!(output.buffered_records, 2);
+        output.flush();
+        assert_eq!(output.failure.as_ref().unwrap().lost, 2);
+
+        assert_eq!(output.write_message("kept\n", Level::Info, &config, &render_notice), None);
+        assert_eq!(output.failure.as_ref().unwrap().buffered, ["kept\n"]);
+    }
+
+    // Verifies that each durability mode syncs only when it is due.
+    #[test]
+    fn test_durability() {
+        let log_path = Path::new("test_durability.log");
+        let _file_cleaner = FileCleaner { file_name: "test_durability.log" };
+        let mut output = Output::open(log_path, &Config::default(), config::OutputFormat::PlainText);
+
+        for _ in 0..2 {
+            output.record_written(Level::Info, &config::Durability::EveryRecords(3)).unwrap();
+        }
+        assert_eq!(output.unsynced_records, 2);
+        output.record_written(Level::Info, &config::Durability::EveryRecords(3)).unwrap();
+        assert_eq!(output.unsynced_records, 0);
+
+        output.record_written(Level::Warn, &config::Durability::OnError).unwrap();
+        assert_eq!(output.unsynced_records, 1);
+        output.record_written(Level::Error, &config::Durability::OnError).unwrap();
+        assert_eq!(output.unsynced_records, 0);
+
+        output.record_written(Level::Info, &config::Durability::OnWriteAfterMillis(60_000)).unwrap();
+        assert_eq!(output.unsynced_records, 1);
+        output.record_written(Level::Info, &config::Durability::OnWriteAfterMillis(0)).unwrap();
+        assert_eq!(output.unsynced_records, 0);
+
+        output.record_written(Level::Error, &config::Durability::Never).unwrap();
+        assert_eq!(output.unsynced_records, 1);
+    }
+
+    // Verifies that records are buffered while the file can't be written and written after the missing records notice.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_write_failure_recovery() {
+        let log_path = Path::new("test_write_failure_recovery.log");
+        let _file_cleaner = FileCleaner { file_name: "test_write_failure_recovery.log" };
+        let config = Config { write_failure: config::WriteFailurePolicy::Memory(1), ..Default::default() };
+        let render_notice = |notice: String| format!("NOTICE {}\n", notice);
+        let mut output = Output::open(log_path, &config, config::OutputFormat::PlainText);
+
+        // Writing to /dev/full fails with "No space left on device"
+        output.container = OutputContainer::File(File::options().write(true).open("/dev/full").unwrap());
+        output.write_message("first\n", log::Level::Info, &config, &render_notice);
+        output.write_message("second\n", log::Level::Info, &config, &render_notice);
+        assert_eq!(output.failure.as_ref().unwrap().buffered, ["second\n"]);
+
+        output.failure.as_mut().unwrap().retry_at = Instant::now();
+        output.write_message("third\n", log::Level::Info, &config, &render_notice);
+
+        assert!(output.failure.is_none());
+        assert_eq!(
+            fs::read_to_string(log_path).unwrap(),
+            "NOTICE 1 record was lost because this log file could not be written\nsecond\nthird\n"
+        );
+    }
+
+    // Verifies the wording of the notice about missing records for each write failure policy.
+    #[test]
+    fn test_failure_notice() {
+        let failure = |missing, lost| WriteFailure { missing, lost, buffered: VecDeque::new(), retry_at: Instant::now(), backoff: INITIAL_BACKOFF };
+        let stderr = config::WriteFailurePolicy::Stderr;
+
+        assert_eq!(failure(0, 0).notice(&stderr), None);
+        assert_eq!(failure(1, 0).notice(&stderr).unwrap(), "1 record was written to stderr because this log file could not be written");
+        assert_eq!(
+            failure(3, 1).notice(&stderr).unwrap(),
+            "3 records were written to stderr and 1 record was lost because this log file could not be written"
+        );
+        assert_eq!(failure(3, 1).notice(&config::WriteFailurePolicy::Drop).unwrap(), "4 records were lost because this log file could not be written");
+    }
+}
@@ -0,0 +1,329 @@
+//! Queries over a directory of log files, see `LogReader::query`.
+//!
+//! # Examples
+//! ```no_run
+//! use log::LevelFilter;
+//! use time::{Date, Month, PrimitiveDateTime, Time};
+//! use logpeek::config::{DateTimeFormat, OutputFormat};
+//! use logpeek::query::Query;
+//! use logpeek::reader::LogReader;
+//!
+//! let date = Date::from_calendar_date(2024, Month::March, 1).unwrap();
+//! let query = Query {
+//!     from: Some(PrimitiveDateTime::new(date, Time::from_hms(10, 0, 0).unwrap()).assume_utc()),
+//!     to: Some(PrimitiveDateTime::new(date, Time::from_hms(10, 15, 0).unwrap()).assume_utc()),
+//!     min_level: LevelFilter::Warn,
+//!     targets: vec![String::from("app::db")],
+//! };
+//!
+//! let reader = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::ISO8601);
+//! for entry in reader.query("logs", &query).unwrap() {
+//!     println!("{:?}", entry.unwrap());
+//! }
+//! ```
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::LevelFilter;
+use time::OffsetDateTime;
+
+use crate::filter::TargetMatcher;
+use crate::reader::{self, file_start, Entries, Entry, LogReader};
+
+/// File names and index times have a resolution of one second, and records are written slightly after
+/// their timestamp was taken, so files and checkpoints are only skipped if they are further off than this.
+const CLOCK_MARGIN: Duration = Duration::from_secs(1);
+
+/// Selects the entries returned by `LogReader::query`. The default matches every entry.
+pub struct Query {
+    /// Only entries at or after this time. Entries without a parsed timestamp never match a time range.
+    pub from: Option<OffsetDateTime>,
+    /// Only entries at or before this time.
+    pub to: Option<OffsetDateTime>,
+    /// Only entries of this level and more severe ones.
+    pub min_level: LevelFilter,
+    /// Only entries whose target matches one of these patterns, which work like `Config::target_allow`.
+    /// Matches every target if empty.
+    pub targets: Vec<String>,
+}
+
+impl Default for Query {
+    fn default() -> Self {
+        Query {
+            from: None,
+            to: None,
+            min_level: LevelFilter::Trace,
+            targets: Vec::new(),
+        }
+    }
+}
+
+impl Query {
+    /// Compiles the target patterns, to match entries that are read some other way than `LogReader::query`.
+    pub fn matcher(&self) -> QueryMatcher<'_> {
+        QueryMatcher {
+            query: self,
+            targets: (!self.targets.is_empty()).then(|| TargetMatcher::new(self.targets.iter().map(String::as_str))),
+        }
+    }
+}
+
+/// Checks entries against a `Query`, see `Query::matcher`.
+pub struct QueryMatcher<'a> {
+    query: &'a Query,
+    targets: Option<TargetMatcher>,
+}
+
+impl QueryMatcher<'_> {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        entry.level <= self.query.min_level
+            && self.targets.as_ref().is_none_or(|targets| targets.matches(&entry.target))
+            && self.in_range(entry)
+    }
+
+    fn in_range(&self, entry: &Entry) -> bool {
+        let Query { from, to, .. } = self.query;
+        if from.is_none() && to.is_none() {
+            return true;
+        }
+
+        entry.timestamp.is_some_and(|timestamp| {
+            from.is_none_or(|from| timestamp >= from) && to.is_none_or(|to| timestamp <= to)
+        })
+    }
+}
+
+impl LogReader {
+    /// Iterates the entries in the log files of `dir` that match `query`, in the order they were written in.
+    /// Like `read_dir`, only files named by `OutputFileName::AutoGenerate` are read, see `reader::log_files`.
+    ///
+    /// These files are named after the time they were created, so files that were rotated before `query.from`
+    /// or created after `query.to` are skipped without reading them. Files with
+    /// an index (see `config::LogIndex`) are read from the last checkpoint before `query.from`.
+    pub fn query<'a>(&'a self, dir: impl AsRef<Path>, query: &'a Query) -> io::Result<QueryResults<'a>> {
+        let files = select_files(reader::log_files(dir)?, query);
+
+        Ok(QueryResults {
+            reader: self,
+            query,
+            matcher: query.matcher(),
+            files: files.into(),
+            current: None,
+        })
+    }
+}
+
+/// Drops the files that can't contain entries in the time range of `query`. `paths` have to be sorted.
+fn select_files(paths: Vec<PathBuf>, query: &Query) -> Vec<PathBuf> {
+    let starts = paths.iter().map(|path| file_start(path)).collect::<Vec<_>>();
+
+    paths.into_iter().enumerate().filter(|(i, _)| {
+        // A file only holds entries until the next file was created
+        let ended_before = match (query.from, starts.get(i + 1).copied().flatten()) {
+            (Some(from), Some(next_start)) => next_start + CLOCK_MARGIN < from,
+            _ => false,
+        };
+        let started_after = match (query.to, starts[*i]) {
+            (Some(to), Some(start)) => start - CLOCK_MARGIN > to,
+            _ => false,
+        };
+        !ended_before && !started_after
+    }).map(|(_, path)| path).collect()
+}
+
+/// Returns the path of the index of a log file.
+pub(crate) fn index_path(log_path: &Path) -> PathBuf {
+    let mut path = log_path.as_os_str().to_owned();
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+/// The checkpoints of a log file that is being written, see `config::LogIndex`.
+pub(crate) struct Index {
+    every: u64,
+    position: u64,
+    next_checkpoint: u64,
+    /// Byte offsets of record starts and the Unix time they were written at.
+    checkpoints: Vec<(u64, i64)>,
+}
+
+impl Index {
+    /// An index for a file that already holds `position` bytes.
+    pub fn new(every: u64, position: u64) -> Index {
+        Index { every: every.max(1), position, next_checkpoint: position, checkpoints: Vec::new() }
+    }
+
+    /// Records that `skipped` bytes were written without a checkpoint, followed by a record of `len` bytes.
+    pub fn record(&mut self, skipped: u64, len: u64) {
+        self.position += skipped;
+        if self.position >= self.next_checkpoint {
+            self.checkpoints.push((self.position, OffsetDateTime::now_utc().unix_timestamp()));
+            self.next_checkpoint = self.position + self.every;
+        }
+        self.position += len;
+    }
+
+    /// Writes the index next to the log file. Each line holds the offset and time of a checkpoint.
+    pub fn write(&self, log_path: &Path) -> io::Result<()> {
+        let contents = self.checkpoints.iter()
+            .map(|(offset, time)| format!("{} {}\n", offset, time))
+            .collect::<String>();
+        fs::write(index_path(log_path), contents)
+    }
+}
+
+/// Returns the offset of the last checkpoint in the index of `log_path` that was written before `from`.
+/// Falls back to the start of the file if there is no usable index.
+fn seek_offset(log_path: &Path, from: OffsetDateTime) -> u64 {
+    let Ok(contents) = fs::read_to_string(index_path(log_path)) else { return 0 };
+    let before = (from - CLOCK_MARGIN).unix_timestamp();
+
+    let mut offset = 0;
+    for line in contents.lines() {
+        let Some((checkpoint, time)) = line.split_once(' ') else { return 0 };
+        let (Ok(checkpoint), Ok(time)) = (checkpoint.parse::<u64>(), time.parse::<i64>()) else { return 0 };
+        if time >= before {
+            break;
+        }
+        offset = checkpoint;
+    }
+    offset
+}
+
+/// The entries matching a `Query`, see `LogReader::query`.
+pub struct QueryResults<'a> {
+    reader: &'a LogReader,
+    query: &'a Query,
+    matcher: QueryMatcher<'a>,
+    files: VecDeque<PathBuf>,
+    current: Option<Entries<'a>>,
+}
+
+impl QueryResults<'_> {
+    fn open(&self, path: &Path) -> io::Result<File> {
+        let mut file = File::open(path)?;
+        if let Some(from) = self.query.from {
+            let offset = seek_offset(path, from);
+            if offset > 0 && offset <= file.metadata()?.len() {
+                file.seek(SeekFrom::Start(offset))?;
+            }
+        }
+        Ok(file)
+    }
+}
+
+impl Iterator for QueryResults<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entries = match &mut self.current {
+                Some(entries) => entries,
+                None => {
+                    let path = self.files.pop_front()?;
+                    let file = match self.open(&path) {
+                        Ok(file) => file,
+                        Err(err) => return Some(Err(err)),
+                    };
+                    self.current.insert(self.reader.entries(BufReader::new(file)))
+                },
+            };
+
+            match entries.next() {
+                Some(Ok(entry)) => if self.matcher.matches(&entry) {
+                    return Some(Ok(entry));
+                },
+                Some(Err(err)) => return Some(Err(err)),
+                None => self.current = None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Date, Month, PrimitiveDateTime, Time};
+    use time::format_description::well_known::Iso8601;
+    use crate::config::{DateTimeFormat, OutputFormat};
+    use super::*;
+
+    // Removes the test directory after the test is done.
+    struct DirCleaner {
+        dir: &'static str,
+    }
+
+    impl Drop for DirCleaner {
+        fn drop(&mut self) {
+            fs::remove_dir_all(self.dir).unwrap();
+        }
+    }
+
+    fn at(hour: u8, minute: u8) -> OffsetDateTime {
+        let date = Date::from_calendar_date(2024, Month::March, 1).unwrap();
+        PrimitiveDateTime::new(date, Time::from_hms(hour, minute, 0).unwrap()).assume_utc()
+    }
+
+    fn line(time: OffsetDateTime, level: &str, target: &str, message: &str) -> String {
+        format!("{} {} {} - {}\n", time.format(&Iso8601::DEFAULT).unwrap(), level, target, message)
+    }
+
+    // Verifies that only the files overlapping the time range are read.
+    #[test]
+    fn test_select_files() {
+        let paths = ["2024_03_01_09_00_00.log", "2024_03_01_10_00_00.log", "2024_03_01_11_00_00.log", "custom.log"]
+            .map(PathBuf::from).to_vec();
+        let query = Query { from: Some(at(10, 30)), to: Some(at(10, 45)), ..Default::default() };
+
+        assert_eq!(select_files(paths.clone(), &query), [PathBuf::from("2024_03_01_10_00_00.log"), PathBuf::from("custom.log")]);
+        assert_eq!(select_files(paths.clone(), &Query::default()), paths);
+    }
+
+    // Verifies that queries filter by level, target and time, and use the index to skip the start of a file.
+    #[test]
+    fn test_query() {
+        let dir = "test_query";
+        let _dir_cleaner = DirCleaner { dir };
+        fs::create_dir_all(dir).unwrap();
+        let log_path = Path::new(dir).join("2024_03_01_09_00_00.log");
+
+        let early = line(at(9, 0), "ERROR", "app::db", "early");
+        let contents = [
+            early.clone(),
+            line(at(10, 5), "ERROR", "app::db::pool", "match"),
+            line(at(10, 6), "INFO", "app::db", "too verbose"),
+            line(at(10, 7), "WARN", "app::http", "other target"),
+            line(at(10, 20), "ERROR", "app::db", "too late"),
+        ].concat();
+        fs::write(&log_path, contents).unwrap();
+        // The file of a routing rule with `RouteMode::Also` repeats records of the main log file
+        fs::write(Path::new(dir).join("errors.log"), line(at(10, 5), "ERROR", "app::db::pool", "match")).unwrap();
+        // The index claims that the second entry was written at 8:30, so a query from 9:00 starts reading there
+        fs::write(index_path(&log_path), format!("0 {}\n{} {}\n", at(8, 0).unix_timestamp(), early.len(), at(8, 30).unix_timestamp())).unwrap();
+
+        let reader = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::ISO8601);
+        let query = Query {
+            from: Some(at(9, 0)),
+            to: Some(at(10, 15)),
+            min_level: LevelFilter::Warn,
+            targets: vec![String::from("app::db")],
+        };
+        let messages = |query: &Query| reader.query(dir, query).unwrap().map(|entry| entry.unwrap().message).collect::<Vec<_>>();
+
+        assert_eq!(messages(&query), ["match"]);
+        assert_eq!(messages(&Query { from: None, ..query }), ["early", "match"]);
+    }
+
+    // Verifies that the index records a checkpoint at the first record after every interval.
+    #[test]
+    fn test_index_checkpoints() {
+        let mut index = Index::new(100, 20);
+        index.record(0, 60);
+        index.record(10, 60);
+        index.record(0, 60);
+
+        assert_eq!(index.checkpoints.iter().map(|(offset, _)| *offset).collect::<Vec<_>>(), [20, 150]);
+    }
+}
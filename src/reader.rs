@@ -0,0 +1,679 @@
+//! Parses log files written by logpeek back into structured entries.
+//!
+//! The reader understands the default plain text layout (with any combination of the source location, module path,
+//! thread and process id options), logfmt and GELF files. Plain text files written with a custom `LineTemplate`
+//! can't be parsed. The escape sequences written by `Escaping::Escape`, the default for files, are reversed, see
+//! `LogReader::escaping`. Continuation lines written by `Escaping::Indent`, `Escaping::Marker` or `Escaping::None`
+//! are joined back into the message of their entry.
+//!
+//! # Examples
+//! ```no_run
+//! use logpeek::config::{DateTimeFormat, OutputFormat};
+//! use logpeek::reader::LogReader;
+//!
+//! let reader = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::ISO8601);
+//! for entry in reader.read_dir("logs").unwrap() {
+//!     let entry = entry.unwrap();
+//!     if entry.level == log::Level::Error {
+//!         println!("{} {}: {}", entry.time, entry.target, entry.message);
+//!     }
+//! }
+//! ```
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::Level;
+use time::format_description::well_known::{Iso8601, Rfc2822, Rfc3339};
+use time::format_description::{self, OwnedFormatItem};
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+use crate::config::{DateTimeFormat, Escaping, OutputFormat};
+use crate::escape;
+use crate::format::{unreserved_key, GELF_RESERVED_FIELDS, LOGFMT_RESERVED_FIELDS};
+use crate::logger::LOG_NAME_FORMAT;
+
+/// The indentation of continuation lines written by `Escaping::Indent`.
+const CONTINUATION_INDENT: &str = "    ";
+
+/// A record parsed from a log file. Fields that weren't written are `None` or empty.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// The timestamp as it was written.
+    pub time: String,
+    /// The parsed timestamp. `None` if it couldn't be parsed, e.g. because a custom `DateTimeFormat` has no date.
+    /// Timestamps without an offset are assumed to be in UTC, see `LogReader::assume_offset`.
+    pub timestamp: Option<OffsetDateTime>,
+    pub level: Level,
+    pub target: String,
+    pub module_path: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub thread_name: Option<String>,
+    pub thread_id: Option<String>,
+    pub process_id: Option<u32>,
+    pub message: String,
+    pub key_values: Vec<(String, String)>,
+}
+
+impl Entry {
+    fn new(time: String, timestamp: Option<OffsetDateTime>, level: Level) -> Entry {
+        Entry {
+            time,
+            timestamp,
+            level,
+            target: String::new(),
+            module_path: None,
+            file: None,
+            line: None,
+            thread_name: None,
+            thread_id: None,
+            process_id: None,
+            message: String::new(),
+            key_values: Vec::new(),
+        }
+    }
+}
+
+enum TimeFormat {
+    Iso8601,
+    Rfc3339,
+    Rfc2822,
+    Custom(OwnedFormatItem),
+}
+
+/// Parses log files written with the given output format and `DateTimeFormat`.
+pub struct LogReader {
+    format: OutputFormat,
+    time_format: TimeFormat,
+    /// How many space separated words the timestamp of a plain text line takes up.
+    time_words: usize,
+    /// The offset of timestamps that were written without one.
+    assumed_offset: UtcOffset,
+    /// The prefix of continuation lines.
+    continuation: String,
+    /// Whether plain text fields were written with `Escaping::Escape`.
+    unescape: bool,
+}
+
+impl LogReader {
+    /// # Panics
+    /// This function will panic if the custom `DateTimeFormat` is invalid.
+    pub fn new(format: OutputFormat, datetime_format: &DateTimeFormat) -> LogReader {
+        let (time_format, time_words) = match datetime_format {
+            DateTimeFormat::ISO8601 => (TimeFormat::Iso8601, 1),
+            DateTimeFormat::RFC3339 => (TimeFormat::Rfc3339, 1),
+            // e.g. `Sat, 18 Oct 2025 18:21:30 +0000`
+            DateTimeFormat::RFC2822 => (TimeFormat::Rfc2822, 6),
+            DateTimeFormat::Custom(format_str) => {
+                let format = format_description::parse_owned::<1>(format_str).unwrap_or_else(|err| {
+                    panic!("Invalid custom time format description: {:?}", err);
+                });
+                (TimeFormat::Custom(format), format_str.matches(' ').count() + 1)
+            },
+        };
+
+        LogReader { format, time_format, time_words, assumed_offset: UtcOffset::UTC, continuation: CONTINUATION_INDENT.to_string(), unescape: true }
+    }
+
+    /// Sets the offset of timestamps written without one, which are assumed to be in UTC otherwise. Use this for
+    /// files written with `TimeZone::Local` and a custom `DateTimeFormat` that leaves out the offset.
+    pub fn assume_offset(mut self, offset: UtcOffset) -> LogReader {
+        self.assumed_offset = offset;
+        self
+    }
+
+    /// Sets the `Escaping` plain text files were written with. Defaults to `Escaping::Escape`, like
+    /// `Config::file_escaping`, whose escape sequences are reversed. Values written with any other escaping are
+    /// returned as they were written, apart from joining continuation lines.
+    pub fn escaping(mut self, escaping: &Escaping) -> LogReader {
+        self.unescape = *escaping == Escaping::Escape;
+        match escaping {
+            Escaping::Marker(marker) => self.continuation_marker(marker),
+            _ => self,
+        }
+    }
+
+    /// Sets the prefix of continuation lines for files written with `Escaping::Marker`. Lines starting with it
+    /// never start an entry. Defaults to the four spaces of `Escaping::Indent`. Like `escaping`, this turns off
+    /// reversing `Escaping::Escape`.
+    pub fn continuation_marker(mut self, marker: &str) -> LogReader {
+        self.continuation = marker.to_string();
+        self.unescape = false;
+        self
+    }
+
+    /// Iterates the entries of a log file, or of the log files in a directory like `read_dir`.
+    pub fn read_path(&self, path: impl AsRef<Path>) -> io::Result<Entries<'_>> {
+        if path.as_ref().is_dir() {
+            self.read_dir(path)
+        } else {
+            self.read_file(path)
+        }
+    }
+
+    /// Iterates the entries of a single log file.
+    pub fn read_file(&self, path: impl AsRef<Path>) -> io::Result<Entries<'_>> {
+        Ok(self.entries(BufReader::new(File::open(path)?)))
+    }
+
+    /// Iterates the entries of the log files named by `OutputFileName::AutoGenerate` in the directory, in the order
+    /// they were written in, see `log_files`. Use `read_file` for other files, e.g. the files of routing rules.
+    pub fn read_dir(&self, path: impl AsRef<Path>) -> io::Result<Entries<'_>> {
+        let paths = log_files(path)?;
+        Ok(Entries { parser: EntryParser::new(self), current: None, paths: paths.into(), line: String::new() })
+    }
+
+    /// Iterates the entries read from `source`, e.g. a `DecryptingReader` for an encrypted file.
+    pub fn entries<'a>(&'a self, source: impl BufRead + 'a) -> Entries<'a> {
+        Entries { parser: EntryParser::new(self), current: Some(Box::new(source)), paths: VecDeque::new(), line: String::new() }
+    }
+
+    /// Parses a single line. Returns `None` if it doesn't start a new entry.
+    pub fn parse_line(&self, line: &str) -> Option<Entry> {
+        let line = strip_hash(line);
+
+        match self.format {
+            OutputFormat::PlainText => self.parse_plain(line),
+            OutputFormat::Logfmt => self.parse_logfmt(line),
+            OutputFormat::Gelf => parse_gelf(line),
+        }
+    }
+
+    fn parse_time(&self, time: &str) -> Option<OffsetDateTime> {
+        match &self.time_format {
+            TimeFormat::Iso8601 => OffsetDateTime::parse(time, &Iso8601::DEFAULT).ok(),
+            TimeFormat::Rfc3339 => OffsetDateTime::parse(time, &Rfc3339).ok(),
+            TimeFormat::Rfc2822 => OffsetDateTime::parse(time, &Rfc2822).ok(),
+            TimeFormat::Custom(format) => OffsetDateTime::parse(time, format).ok()
+                .or_else(|| PrimitiveDateTime::parse(time, format).ok().map(|time| time.assume_offset(self.assumed_offset))),
+        }
+    }
+
+    fn parse_plain(&self, line: &str) -> Option<Entry> {
+        if line.starts_with(&self.continuation) {
+            return None;
+        }
+
+        let mut words = line.splitn(self.time_words + 2, ' ');
+        let time = words.by_ref().take(self.time_words).collect::<Vec<_>>().join(" ");
+        let level = Level::from_str(words.next()?).ok()?;
+        let rest = words.next()?;
+        let (head, message) = rest.split_once(" - ").or_else(|| Some((rest.strip_suffix(" -")?, "")))?;
+
+        let mut entry = Entry::new(time, None, level);
+        entry.timestamp = self.parse_time(&entry.time);
+        entry.message = self.unescape(message);
+
+        let mut fields = head.split(' ');
+        entry.target = self.unescape(fields.next()?);
+        while let Some(field) = fields.next() {
+            if field.starts_with('[') {
+                // Thread names can contain spaces
+                let mut thread = field.to_string();
+                while !thread.ends_with(']') {
+                    thread.push(' ');
+                    thread.push_str(fields.next()?);
+                }
+                let thread = &thread[1..thread.len() - 1];
+                match thread.rsplit_once('#') {
+                    Some((name, id)) => {
+                        entry.thread_name = Some(self.unescape(name));
+                        entry.thread_id = Some(id.to_string());
+                    },
+                    None if !thread.is_empty() && thread.chars().all(|c| c.is_ascii_digit()) => entry.thread_id = Some(thread.to_string()),
+                    None => entry.thread_name = Some(self.unescape(thread)),
+                }
+            } else if let Some(pid) = field.strip_prefix("pid=") {
+                entry.process_id = pid.parse().ok();
+            } else if let Some((file, line)) = field.rsplit_once(':').filter(|(file, line)| {
+                // The source location is left out for records without a file, a `::` belongs to a module path
+                !file.is_empty() && !file.ends_with(':') && (line.is_empty() || line.parse::<u32>().is_ok())
+            }) {
+                entry.file = Some(self.unescape(file));
+                entry.line = line.parse().ok();
+            } else {
+                entry.module_path = Some(self.unescape(field));
+            }
+        }
+
+        Some(entry)
+    }
+
+    fn unescape(&self, value: &str) -> String {
+        if self.unescape {
+            escape::unescape(value).into_owned()
+        } else {
+            value.to_string()
+        }
+    }
+
+    fn parse_logfmt(&self, line: &str) -> Option<Entry> {
+        let pairs = parse_logfmt_pairs(line)?;
+        let level = pairs.iter().find(|(key, _)| key == "level").and_then(|(_, level)| Level::from_str(level).ok())?;
+
+        let mut entry = Entry::new(String::new(), None, level);
+        for (key, value) in pairs {
+            match key.as_str() {
+                "ts" => {
+                    entry.timestamp = self.parse_time(&value);
+                    entry.time = value;
+                },
+                "level" => {},
+                "target" => entry.target = value,
+                "module" => entry.module_path = Some(value),
+                "file" => entry.file = Some(value),
+                "line" => entry.line = value.parse().ok(),
+                "thread" => entry.thread_name = Some(value),
+                "thread_id" => entry.thread_id = Some(value),
+                "pid" => entry.process_id = value.parse().ok(),
+                "msg" => entry.message = value,
+                "sha256" if is_hash(&value) => {},
+                _ => entry.key_values.push((unreserved_key(&key, &LOGFMT_RESERVED_FIELDS).to_string(), value)),
+            }
+        }
+        Some(entry)
+    }
+}
+
+/// Returns the log files in `dir` named by `OutputFileName::AutoGenerate`, oldest first. Other `.log` files, like the
+/// files of routing rules, are left out, since the main log files usually contain their records as well.
+pub fn log_files(dir: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|dir_entry| dir_entry.map(|dir_entry| dir_entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    paths.retain(|path| path.is_file() && file_start(path).is_some());
+    paths.sort();
+    Ok(paths)
+}
+
+/// Returns the creation time encoded in the name of an `AutoGenerate` log file.
+pub(crate) fn file_start(path: &Path) -> Option<OffsetDateTime> {
+    let format = format_description::parse_borrowed::<1>(LOG_NAME_FORMAT).ok()?;
+    let name = path.file_name()?.to_str()?;
+    PrimitiveDateTime::parse(name, &format).ok().map(PrimitiveDateTime::assume_utc)
+}
+
+/// Removes the ` #sha256=...` suffix added to plain text lines by `HashChain::HmacSha256`.
+fn strip_hash(line: &str) -> &str {
+    match line.rsplit_once(" #sha256=") {
+        Some((record, hash)) if is_hash(hash) => record,
+        _ => line,
+    }
+}
+
+/// Whether `value` is a hash added by `HashChain::HmacSha256`.
+fn is_hash(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn parse_logfmt_pairs(line: &str) -> Option<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if_eq(&' ').is_some() {}
+        if chars.peek().is_none() {
+            return Some(pairs);
+        }
+
+        let mut key = String::new();
+        for c in chars.by_ref() {
+            if c == '=' {
+                break;
+            }
+            key.push(c);
+        }
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => value.push(unescape(&mut chars)?),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ' ') {
+                value.push(c);
+            }
+        }
+        pairs.push((key, value));
+    }
+}
+
+/// Unescapes the character after a backslash in logfmt and JSON strings.
+fn unescape(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<char> {
+    Some(match chars.next()? {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        'b' => '\u{8}',
+        'f' => '\u{c}',
+        'u' => {
+            let code = parse_hex4(chars)?;
+            // Characters outside the basic plane are written as a surrogate pair in JSON
+            if (0xd800..0xdc00).contains(&code) && chars.next_if_eq(&'\\').is_some() && chars.next_if_eq(&'u').is_some() {
+                let low = parse_hex4(chars)?;
+                return char::from_u32(0x10000 + ((code - 0xd800) << 10) + (low.checked_sub(0xdc00)?));
+            }
+            return char::from_u32(code);
+        },
+        c => c,
+    })
+}
+
+fn parse_hex4(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<u32> {
+    let hex = chars.by_ref().take(4).collect::<String>();
+    u32::from_str_radix(&hex, 16).ok()
+}
+
+/// Parses the flat JSON object written for the GELF file format.
+fn parse_gelf(line: &str) -> Option<Entry> {
+    let mut chars = line.trim().chars().peekable();
+    if chars.next()? != '{' {
+        return None;
+    }
+
+    let mut fields = Vec::new();
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        if chars.next_if_eq(&'}').is_some() {
+            break;
+        }
+
+        let key = parse_json_string(&mut chars)?;
+        while chars.next_if(|c| c.is_whitespace() || *c == ':').is_some() {}
+        let value = if chars.peek() == Some(&'"') {
+            parse_json_string(&mut chars)?
+        } else {
+            let mut value = String::new();
+            while let Some(c) = chars.next_if(|c| *c != ',' && *c != '}' && !c.is_whitespace()) {
+                value.push(c);
+            }
+            value
+        };
+        fields.push((key, value));
+    }
+
+    let level = match fields.iter().find(|(key, _)| key == "level")?.1.parse::<u8>().ok()? {
+        0..=3 => Level::Error,
+        4 => Level::Warn,
+        5 | 6 => Level::Info,
+        _ => Level::Debug,
+    };
+
+    let mut entry = Entry::new(String::new(), None, level);
+    let mut short_message = String::new();
+    for (key, value) in fields {
+        match key.as_str() {
+            "version" | "host" | "level" => {},
+            "short_message" => short_message = value,
+            "full_message" => entry.message = value,
+            "timestamp" => {
+                entry.timestamp = value.parse::<f64>().ok()
+                    .and_then(|seconds| OffsetDateTime::from_unix_timestamp_nanos((seconds * 1e9) as i128).ok());
+                entry.time = value;
+            },
+            "_target" => entry.target = value,
+            "_file" => entry.file = Some(value),
+            "_line" => entry.line = value.parse().ok(),
+            "_module" => entry.module_path = Some(value),
+            "_thread" => entry.thread_name = Some(value),
+            "_thread_id" => entry.thread_id = Some(value),
+            "_pid" => entry.process_id = value.parse().ok(),
+            "_sha256" if is_hash(&value) => {},
+            _ => {
+                let key = key.strip_prefix('_').unwrap_or(&key);
+                let key = unreserved_key(key, &GELF_RESERVED_FIELDS);
+                entry.key_values.push((key.to_string(), value));
+            },
+        }
+    }
+    if entry.message.is_empty() {
+        entry.message = short_message;
+    }
+
+    Some(entry)
+}
+
+fn parse_json_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+
+    let mut value = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => value.push(unescape(chars)?),
+            c => value.push(c),
+        }
+    }
+}
+
+/// Builds entries from lines that are fed one at a time, e.g. while following a file that is still being written.
+///
+/// Lines that don't start an entry are continuation lines and are appended to the message of the entry before
+/// them. Such lines before the first entry, e.g. the rest of a record cut off by a rotation, are skipped.
+pub struct EntryParser<'a> {
+    reader: &'a LogReader,
+    pending: Option<Entry>,
+}
+
+impl<'a> EntryParser<'a> {
+    pub fn new(reader: &'a LogReader) -> EntryParser<'a> {
+        EntryParser { reader, pending: None }
+    }
+
+    /// Feeds a line, without its line break. Returns the previous entry once this line starts a new one.
+    pub fn push(&mut self, line: &str) -> Option<Entry> {
+        match self.reader.parse_line(line) {
+            Some(entry) => self.pending.replace(entry),
+            None => {
+                if let Some(pending) = &mut self.pending {
+                    let line = strip_hash(line);
+                    pending.message.push('\n');
+                    pending.message.push_str(line.strip_prefix(self.reader.continuation.as_str()).unwrap_or(line));
+                }
+                None
+            },
+        }
+    }
+
+    /// Returns the last entry. Call this at the end of a file, since entries don't continue into the next one.
+    pub fn finish(&mut self) -> Option<Entry> {
+        self.pending.take()
+    }
+}
+
+/// An iterator over the entries of one or more log files, see `LogReader` and `EntryParser`.
+pub struct Entries<'a> {
+    parser: EntryParser<'a>,
+    current: Option<Box<dyn BufRead + 'a>>,
+    paths: VecDeque<PathBuf>,
+    line: String,
+}
+
+impl Iterator for Entries<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let source = match &mut self.current {
+                Some(source) => source,
+                None => {
+                    let path = self.paths.pop_front()?;
+                    match File::open(&path) {
+                        Ok(file) => self.current.insert(Box::new(BufReader::new(file))),
+                        Err(err) => return Some(Err(err)),
+                    }
+                },
+            };
+
+            self.line.clear();
+            match source.read_line(&mut self.line) {
+                Ok(0) => {
+                    self.current = None;
+                    if let Some(entry) = self.parser.finish() {
+                        return Some(Ok(entry));
+                    }
+                },
+                Ok(_) => {
+                    let line = self.line.strip_suffix('\n').unwrap_or(&self.line);
+                    let line = line.strip_suffix('\r').unwrap_or(line);
+
+                    if let Some(entry) = self.parser.push(line) {
+                        return Some(Ok(entry));
+                    }
+                },
+                Err(err) => {
+                    self.current = None;
+                    return Some(Err(err));
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(reader: &LogReader, contents: &str) -> Vec<Entry> {
+        reader.entries(contents.as_bytes()).collect::<io::Result<Vec<_>>>().unwrap()
+    }
+
+    // Verifies that every optional field of the default plain text layout is parsed.
+    #[test]
+    fn test_plain_text_fields() {
+        let reader = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::ISO8601);
+
+        let entries = read(&reader, concat!(
+            "2024-01-01T10:00:00.000000000Z INFO app - started\n",
+            "2024-01-01T10:00:01.000000000Z WARN app::db app::db::pool src/db/pool.rs:42 [worker 1#7] pid=99 - pool - exhausted\n",
+            "2024-01-01T10:00:02.000000000Z ERROR app [12] -\n",
+        ));
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].timestamp, OffsetDateTime::from_unix_timestamp(1704103200).ok());
+        assert_eq!((entries[0].level, entries[0].target.as_str(), entries[0].message.as_str()), (Level::Info, "app", "started"));
+
+        let entry = &entries[1];
+        assert_eq!(entry.module_path.as_deref(), Some("app::db::pool"));
+        assert_eq!((entry.file.as_deref(), entry.line), (Some("src/db/pool.rs"), Some(42)));
+        assert_eq!((entry.thread_name.as_deref(), entry.thread_id.as_deref()), (Some("worker 1"), Some("7")));
+        assert_eq!(entry.process_id, Some(99));
+        assert_eq!(entry.message, "pool - exhausted");
+
+        assert_eq!((entries[2].thread_id.as_deref(), entries[2].message.as_str()), (Some("12"), ""));
+    }
+
+    // Verifies that the escape sequences of `Escaping::Escape` are reversed, and kept for other escaping modes.
+    #[test]
+    fn test_unescape() {
+        let line = "2024-01-01T10:00:00.000000000Z INFO app\\\\x src\\\\db.rs: [w\\tx#3] - a\\nb \\u{1b}[0m C:\\\\logs\n";
+
+        let entries = read(&LogReader::new(OutputFormat::PlainText, &DateTimeFormat::ISO8601), line);
+        assert_eq!(entries[0].target, "app\\x");
+        assert_eq!((entries[0].file.as_deref(), entries[0].line), (Some("src\\db.rs"), None));
+        assert_eq!(entries[0].thread_name.as_deref(), Some("w\tx"));
+        assert_eq!(entries[0].message, "a\nb \u{1b}[0m C:\\logs");
+
+        let entries = read(&LogReader::new(OutputFormat::PlainText, &DateTimeFormat::ISO8601).escaping(&Escaping::Indent), line);
+        assert_eq!(entries[0].message, "a\\nb \\u{1b}[0m C:\\\\logs");
+    }
+
+    // Verifies that continuation lines are joined and hash chain suffixes are removed.
+    #[test]
+    fn test_multi_line_messages() {
+        let reader = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::RFC2822);
+        let hash = "0".repeat(64);
+
+        let entries = read(&reader, &format!(concat!(
+            "    cut off by a rotation\n",
+            "Mon, 01 Jan 2024 10:00:00 +0000 ERROR app - request failed\n",
+            "    caused by: timeout\n",
+            "raw second line #sha256={}\n",
+            "Mon, 01 Jan 2024 10:00:01 +0000 INFO app - done\n",
+        ), hash));
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].time, "Mon, 01 Jan 2024 10:00:00 +0000");
+        assert!(entries[0].timestamp.is_some());
+        assert_eq!(entries[0].message, "request failed\ncaused by: timeout\nraw second line");
+        assert_eq!(entries[1].message, "done");
+    }
+
+    // Verifies that marked continuation lines are joined, even if they look like an entry.
+    #[test]
+    fn test_continuation_marker() {
+        let reader = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::ISO8601).continuation_marker("| ");
+
+        let entries = read(&reader, concat!(
+            "2024-01-01T10:00:00.000000000Z ERROR app - request failed\n",
+            "| 2024-01-01T10:00:01.000000000Z INFO app - forged\n",
+            "2024-01-01T10:00:02.000000000Z INFO app - done\n",
+        ));
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].message, "request failed\n2024-01-01T10:00:01.000000000Z INFO app - forged");
+    }
+
+    // Verifies that custom time formats without a date still produce entries.
+    #[test]
+    fn test_custom_time_format() {
+        let reader = LogReader::new(OutputFormat::PlainText, &DateTimeFormat::Custom("[hour]:[minute]:[second] [period]"));
+
+        let entries = read(&reader, "10:00:00 AM DEBUG app - tick\n");
+
+        assert_eq!((entries[0].time.as_str(), entries[0].timestamp), ("10:00:00 AM", None));
+        assert_eq!(entries[0].level, Level::Debug);
+    }
+
+    // Verifies that logfmt lines are parsed, including quoted values and key-values.
+    #[test]
+    fn test_logfmt() {
+        let reader = LogReader::new(OutputFormat::Logfmt, &DateTimeFormat::RFC3339);
+
+        let entries = read(&reader, "ts=2024-01-01T10:00:00Z level=warn target=app line=7 msg=\"disk \\\"almost\\\" full\\nreally\" user=bob _msg=x __level=y _raw=z\n");
+
+        assert_eq!(entries[0].level, Level::Warn);
+        assert!(entries[0].timestamp.is_some());
+        assert_eq!(entries[0].line, Some(7));
+        assert_eq!(entries[0].message, "disk \"almost\" full\nreally");
+        assert_eq!(entries[0].key_values, [
+            (String::from("user"), String::from("bob")),
+            (String::from("msg"), String::from("x")),
+            (String::from("_level"), String::from("y")),
+            (String::from("_raw"), String::from("z")),
+        ]);
+    }
+
+    // Verifies that GELF lines are parsed back into entries.
+    #[test]
+    fn test_gelf() {
+        let reader = LogReader::new(OutputFormat::Gelf, &DateTimeFormat::ISO8601);
+
+        let entries = read(&reader, concat!(
+            r#"{"version":"1.1","host":"web1","short_message":"failed","full_message":"failed\nbadly","timestamp":1704103200.500,"#,
+            r#""level":3,"_target":"app","_pid":12,"__id":"x","__target":"other","_user":"bob é","__raw":"y","___pid":"z"}"#, "\n",
+        ));
+
+        let entry = &entries[0];
+        assert_eq!((entry.level, entry.message.as_str()), (Level::Error, "failed\nbadly"));
+        assert_eq!(entry.timestamp, OffsetDateTime::from_unix_timestamp_nanos(1_704_103_200_500_000_000).ok());
+        assert_eq!(entry.process_id, Some(12));
+        assert_eq!(entry.target, "app");
+        assert_eq!(entry.key_values, [
+            (String::from("id"), String::from("x")),
+            (String::from("target"), String::from("other")),
+            (String::from("user"), String::from("bob é")),
+            (String::from("_raw"), String::from("y")),
+            (String::from("_pid"), String::from("z")),
+        ]);
+    }
+}
@@ -0,0 +1,136 @@
+//! Snapshot of a `log::Record` taken on the calling thread.
+use std::sync::OnceLock;
+use std::{process, thread};
+
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, Record};
+use time::OffsetDateTime;
+
+use crate::context;
+
+/// Everything the output formats need from a record, captured at the call site so that
+/// thread information stays correct even if the record is written later.
+#[derive(Clone)]
+pub(crate) struct LogRecord {
+    pub timestamp: OffsetDateTime,
+    pub time: Option<String>,
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    pub module_path: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub thread_name: Option<String>,
+    pub thread_id: String,
+    pub process_id: u32,
+    pub key_values: Vec<(String, String)>,
+}
+
+struct KeyValueCollector<'a>(&'a mut Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for KeyValueCollector<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+impl LogRecord {
+    /// Captures the given record. `time` is the already formatted timestamp, if one could be obtained.
+    pub fn capture(record: &Record, time: Option<String>) -> LogRecord {
+        let current_thread = thread::current();
+
+        let mut record_key_values = Vec::new();
+        // Visiting only fails if the collector returns an error, which it never does
+        let _ = record.key_values().visit(&mut KeyValueCollector(&mut record_key_values));
+
+        // Context fields come first, fields set on the record itself take precedence
+        let mut key_values = context::fields();
+        key_values.retain(|(key, _)| !record_key_values.iter().any(|(record_key, _)| record_key == key));
+        key_values.append(&mut record_key_values);
+
+        LogRecord {
+            timestamp: OffsetDateTime::now_utc(),
+            time,
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            module_path: record.module_path().map(str::to_string),
+            file: record.file().map(str::to_string),
+            line: record.line(),
+            thread_name: current_thread.name().map(str::to_string),
+            thread_id: thread_id_string(current_thread.id()),
+            process_id: process::id(),
+            key_values,
+        }
+    }
+}
+
+#[cfg(test)]
+impl LogRecord {
+    /// A record with only a target and a message, to be completed with struct update syntax.
+    pub fn test(target: &str, message: &str) -> LogRecord {
+        LogRecord {
+            timestamp: OffsetDateTime::UNIX_EPOCH,
+            time: Some(String::from("2024-01-01T00:00:00Z")),
+            level: Level::Info,
+            target: target.to_string(),
+            message: message.to_string(),
+            module_path: None,
+            file: None,
+            line: None,
+            thread_name: None,
+            thread_id: String::from("1"),
+            process_id: 1,
+            key_values: Vec::new(),
+        }
+    }
+}
+
+/// Shortens an absolute source path to start at the crate root, i.e. the directory containing `src`.
+/// `/home/me/.cargo/registry/src/index.crates.io-6f17d22bba15001f/serde-1.0.193/src/de/mod.rs` becomes
+/// `serde-1.0.193/src/de/mod.rs`. Relative paths are returned as they are.
+pub(crate) fn shorten_path(path: &str) -> &str {
+    let is_absolute = path.starts_with('/') || path.starts_with('\\') || path.get(1..3) == Some(":\\") || path.get(1..3) == Some(":/");
+    if !is_absolute {
+        return path;
+    }
+
+    let src_index = match path.rfind("/src/").or_else(|| path.rfind("\\src\\")) {
+        Some(index) => index,
+        None => return path,
+    };
+
+    match path[..src_index].rfind(['/', '\\']) {
+        Some(crate_dir_start) => &path[crate_dir_start + 1..],
+        None => path,
+    }
+}
+
+/// `ThreadId::as_u64` is unstable, so the numeric id is taken from the `Debug` output (`ThreadId(N)`).
+fn thread_id_string(id: thread::ThreadId) -> String {
+    let debug = format!("{:?}", id);
+
+    debug.trim_start_matches("ThreadId(").trim_end_matches(')').to_string()
+}
+
+/// Returns the host name of the machine, looked up once per process.
+pub(crate) fn hostname() -> &'static str {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+
+    HOSTNAME.get_or_init(|| gethostname::gethostname().to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verifies that absolute paths are shortened to the crate root and relative paths are kept.
+    #[test]
+    fn test_shorten_path() {
+        assert_eq!(shorten_path("/home/me/.cargo/registry/src/index.crates.io-6f17d22bba15001f/serde-1.0.193/src/de/mod.rs"), "serde-1.0.193/src/de/mod.rs");
+        assert_eq!(shorten_path("C:\\Users\\me\\app\\src\\main.rs"), "app\\src\\main.rs");
+        assert_eq!(shorten_path("src/main.rs"), "src/main.rs");
+        assert_eq!(shorten_path("/tmp/generated.rs"), "/tmp/generated.rs");
+    }
+}
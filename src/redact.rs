@@ -0,0 +1,186 @@
+//! Redaction of secrets before a record reaches any output, see `config::Redaction`.
+//!
+//! # Examples
+//! ```
+//! use logpeek::config::{Config, Redaction};
+//! use logpeek::redact::Redactor;
+//!
+//! // Masks everything after "session=" in messages
+//! struct SessionRedactor;
+//!
+//! impl Redactor for SessionRedactor {
+//!     fn redact(&self, _key: Option<&str>, value: &mut String) {
+//!         if let Some(start) = value.find("session=") {
+//!             value.replace_range(start + "session=".len().., "[REDACTED]");
+//!         }
+//!     }
+//! }
+//!
+//! let config = Config {
+//!     redaction: Redaction {
+//!         keys: vec!["password", "authorization"],
+//!         redactors: vec![Box::new(SessionRedactor)],
+//!         ..Default::default()
+//!     },
+//!     ..Default::default()
+//! };
+//! ```
+use std::panic::RefUnwindSafe;
+
+use crate::config::Redaction;
+use crate::record::LogRecord;
+
+/// Bearer tokens in e.g. logged `Authorization` headers. For use with `Redaction::patterns`.
+#[cfg(feature = "regex")]
+pub const BEARER_TOKEN_PATTERN: &str = r"(?i)bearer\s+[a-z0-9\-._~+/]+=*";
+
+/// Sequences of 13 to 19 digits, optionally separated by spaces or dashes, as used by payment card numbers.
+/// For use with `Redaction::patterns`.
+#[cfg(feature = "regex")]
+pub const CARD_NUMBER_PATTERN: &str = r"\b(?:\d[ -]?){12,18}\d\b";
+
+/// A custom redaction step, applied to the message and every key-value of each record.
+pub trait Redactor: Send + Sync + RefUnwindSafe {
+    /// Removes secrets from `value` in place. `key` is the name of the key-value, or `None` for the message.
+    fn redact(&self, key: Option<&str>, value: &mut String);
+}
+
+/// The redaction settings from the config, compiled once in `Logger::new`.
+pub(crate) struct Redactions {
+    keys: Vec<String>,
+    #[cfg(feature = "regex")]
+    patterns: Vec<regex::Regex>,
+    redactors: Vec<Box<dyn Redactor>>,
+    mask: &'static str,
+}
+
+impl Redactions {
+    /// Returns `None` if nothing is configured, so records don't pay for redaction they don't use.
+    pub fn new(redaction: Redaction) -> Result<Option<Redactions>, String> {
+        #[cfg(feature = "regex")]
+        let patterns = redaction.patterns.iter()
+            .map(|pattern| regex::Regex::new(pattern).map_err(|err| err.to_string()))
+            .collect::<Result<Vec<_>, String>>()?;
+        #[cfg(feature = "regex")]
+        let has_patterns = !patterns.is_empty();
+        #[cfg(not(feature = "regex"))]
+        let has_patterns = false;
+
+        if redaction.keys.is_empty() && redaction.redactors.is_empty() && !has_patterns {
+            return Ok(None);
+        }
+
+        Ok(Some(Redactions {
+            keys: redaction.keys.iter().map(|key| key.to_lowercase()).collect(),
+            #[cfg(feature = "regex")]
+            patterns,
+            redactors: redaction.redactors,
+            mask: redaction.mask,
+        }))
+    }
+
+    pub fn apply(&self, record: &mut LogRecord) {
+        self.redact_value(None, &mut record.message);
+
+        for (key, value) in record.key_values.iter_mut() {
+            if self.keys.iter().any(|secret_key| secret_key.eq_ignore_ascii_case(key)) {
+                *value = self.mask.to_string();
+                continue;
+            }
+            self.redact_value(Some(key), value);
+        }
+    }
+
+    fn redact_value(&self, key: Option<&str>, value: &mut String) {
+        #[cfg(feature = "regex")]
+        for pattern in &self.patterns {
+            if let std::borrow::Cow::Owned(redacted) = pattern.replace_all(value, regex::NoExpand(self.mask)) {
+                *value = redacted;
+            }
+        }
+
+        for redactor in &self.redactors {
+            redactor.redact(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_values(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    struct UppercaseRedactor;
+
+    impl Redactor for UppercaseRedactor {
+        fn redact(&self, key: Option<&str>, value: &mut String) {
+            if key == Some("token") {
+                *value = value.to_uppercase();
+            }
+        }
+    }
+
+    // Verifies that named key-values are masked case-insensitively and custom redactors see every value.
+    #[test]
+    fn test_keys_and_redactors() {
+        let redactions = Redactions::new(Redaction {
+            keys: vec!["password", "authorization"],
+            redactors: vec![Box::new(UppercaseRedactor)],
+            ..Default::default()
+        }).unwrap().unwrap();
+
+        let mut record = LogRecord {
+            key_values: key_values(&[("user", "bob"), ("Password", "hunter2"), ("token", "abc")]),
+            ..LogRecord::test("app", "login")
+        };
+        redactions.apply(&mut record);
+
+        assert_eq!(record.key_values[0].1, "bob");
+        assert_eq!(record.key_values[1].1, "[REDACTED]");
+        assert_eq!(record.key_values[2].1, "ABC");
+    }
+
+    // Verifies that regex patterns are masked in the message and key-values.
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_patterns() {
+        let redactions = Redactions::new(Redaction {
+            patterns: vec![BEARER_TOKEN_PATTERN, CARD_NUMBER_PATTERN],
+            ..Default::default()
+        }).unwrap().unwrap();
+
+        let mut record = LogRecord {
+            key_values: key_values(&[("header", "Bearer eyJhbGciOi.payload.sig")]),
+            ..LogRecord::test("app", "paid with 4111 1111 1111 1111 on order 12345")
+        };
+        redactions.apply(&mut record);
+
+        assert_eq!(record.message, "paid with [REDACTED] on order 12345");
+        assert_eq!(record.key_values[0].1, "[REDACTED]");
+    }
+
+    // Verifies that the mask is inserted literally, even if it looks like a capture group reference.
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_literal_mask() {
+        let redactions = Redactions::new(Redaction {
+            patterns: vec![r"(?<secret>s3cr3t)"],
+            mask: "$secret$1",
+            ..Default::default()
+        }).unwrap().unwrap();
+
+        let mut record = LogRecord::test("app", "key s3cr3t");
+        redactions.apply(&mut record);
+
+        assert_eq!(record.message, "key $secret$1");
+    }
+
+    // Verifies that an empty configuration compiles to nothing.
+    #[test]
+    fn test_disabled() {
+        assert!(Redactions::new(Redaction::default()).unwrap().is_none());
+    }
+}
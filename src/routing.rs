@@ -0,0 +1,73 @@
+//! Routing rules that send records to additional destinations, see `config::Route`.
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter};
+
+use crate::Config;
+use crate::config::{OutputFormat, Route, RouteDestination, RouteMode};
+use crate::filter::TargetMatcher;
+use crate::output::Output;
+
+pub(crate) enum RouteOutput {
+    Console,
+    MainFile,
+    File(Box<Mutex<Output>>),
+}
+
+pub(crate) struct CompiledRoute {
+    targets: Option<TargetMatcher>,
+    min_level: LevelFilter,
+    pub format: OutputFormat,
+    pub only: bool,
+    pub output: RouteOutput,
+}
+
+/// The routing rules from the config, with their files opened in `Logger::new`.
+pub(crate) struct Router {
+    routes: Vec<CompiledRoute>,
+}
+
+impl Router {
+    /// # Panics
+    /// This function will panic if a route file can't be opened, or if a route targets the main log file
+    /// while `LoggingMode` doesn't include a file.
+    pub fn new(config: &Config, has_main_file: bool, open: impl Fn(&str, OutputFormat) -> Output) -> Router {
+        let routes = config.routes.iter().map(|route: &Route| {
+            let output = match &route.destination {
+                RouteDestination::Console => RouteOutput::Console,
+                RouteDestination::MainFile => {
+                    if !has_main_file {
+                        panic!("A route targets the main log file, but the logging mode doesn't include a file");
+                    }
+                    RouteOutput::MainFile
+                },
+                RouteDestination::File(name) => RouteOutput::File(Box::new(Mutex::new(open(name, route.format)))),
+            };
+
+            CompiledRoute {
+                targets: if route.targets.is_empty() { None } else { Some(TargetMatcher::new(route.targets.iter().copied())) },
+                min_level: route.min_level,
+                format: route.format,
+                only: route.mode == RouteMode::Only,
+                output,
+            }
+        }).collect();
+
+        Router { routes }
+    }
+
+    /// The least severe level that any route accepts.
+    pub fn max_level(&self) -> LevelFilter {
+        self.routes.iter().map(|route| route.min_level).max().unwrap_or(LevelFilter::Off)
+    }
+
+    pub fn matching<'a>(&'a self, level: Level, target: &'a str) -> impl Iterator<Item = &'a CompiledRoute> + 'a {
+        self.routes.iter().filter(move |route| {
+            level <= route.min_level && route.targets.as_ref().is_none_or(|targets| targets.matches(target))
+        })
+    }
+
+    pub fn routes(&self) -> &[CompiledRoute] {
+        &self.routes
+    }
+}
@@ -0,0 +1,171 @@
+//! Probabilistic sampling of records, see `config::SamplingRule`.
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use log::kv::Key;
+use log::Record;
+
+use crate::config::SamplingRule;
+use crate::context;
+use crate::filter::TargetMatcher;
+
+struct CompiledRule {
+    level: Option<log::Level>,
+    target: Option<TargetMatcher>,
+    rate: f64,
+    key: Option<&'static str>,
+}
+
+/// The sampling rules from the config, compiled once in `Logger::new`.
+pub(crate) struct Sampler {
+    rules: Vec<CompiledRule>,
+}
+
+impl Sampler {
+    /// # Panics
+    /// This function will panic if a rate is NaN.
+    pub fn new(rules: &[SamplingRule]) -> Option<Sampler> {
+        if rules.is_empty() {
+            return None;
+        }
+
+        Some(Sampler {
+            rules: rules.iter().map(|rule| {
+                if rule.rate.is_nan() {
+                    panic!("Invalid sampling rate NaN, it must be between 0.0 and 1.0");
+                }
+
+                CompiledRule {
+                    level: rule.level,
+                    target: rule.target.map(|target| TargetMatcher::new([target])),
+                    rate: rule.rate.clamp(0.0, 1.0),
+                    key: rule.key,
+                }
+            }).collect(),
+        })
+    }
+
+    /// Decides whether the record is kept. Only the first matching rule applies, records matching no rule are kept.
+    ///
+    /// This runs before the record is captured, so dropped records never have their message formatted.
+    pub fn keep(&self, record: &Record) -> bool {
+        let rule = match self.rules.iter().find(|rule| {
+            rule.level.is_none_or(|level| level == record.level()) && rule.target.as_ref().is_none_or(|target| target.matches(record.target()))
+        }) {
+            Some(rule) => rule,
+            None => return true,
+        };
+
+        if rule.rate >= 1.0 {
+            return true;
+        }
+        if rule.rate <= 0.0 {
+            return false;
+        }
+
+        let key_value = rule.key.and_then(|key| {
+            record.key_values().get(Key::from_str(key))
+                .map(|value| value.to_string())
+                .or_else(|| context::field(key))
+        });
+
+        let sample = match key_value {
+            Some(value) => unit_interval(fnv1a(value.as_bytes())),
+            None => unit_interval(next_random()),
+        };
+        sample < rule.rate
+    }
+}
+
+/// FNV-1a is stable across processes and versions, so the same key is kept or dropped by every service.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Maps the hash to `[0, 1)` using its upper 53 bits.
+fn unit_interval(hash: u64) -> f64 {
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A per thread xorshift64* generator, seeded from the randomly keyed std hasher.
+fn next_random() -> u64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+    }
+
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545f4914f6cdd1d)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use super::*;
+
+    fn keep(sampler: &Sampler, level: Level, target: &str, key_values: &[(&str, &str)]) -> bool {
+        sampler.keep(&Record::builder()
+            .args(format_args!("sampled"))
+            .level(level)
+            .target(target)
+            .key_values(&key_values)
+            .build())
+    }
+
+    // Verifies that rules only apply to matching levels and targets.
+    #[test]
+    fn test_rule_matching() {
+        let sampler = Sampler::new(&[
+            SamplingRule { level: Some(Level::Trace), target: None, rate: 0.0, key: None },
+            SamplingRule { level: None, target: Some("hyper"), rate: 0.0, key: None },
+        ]).unwrap();
+
+        assert!(!keep(&sampler, Level::Trace, "app", &[]));
+        assert!(!keep(&sampler, Level::Error, "hyper::client", &[]));
+        assert!(keep(&sampler, Level::Debug, "app", &[]));
+    }
+
+    // Verifies that sampling by key keeps or drops every record with the same key value.
+    #[test]
+    fn test_deterministic_by_key() {
+        let sampler = Sampler::new(&[SamplingRule { level: None, target: None, rate: 0.5, key: Some("request_id") }]).unwrap();
+
+        for request_id in ["a", "b", "c", "d", "e", "f"] {
+            let first = keep(&sampler, Level::Debug, "app", &[("request_id", request_id)]);
+            for _ in 0..10 {
+                assert_eq!(keep(&sampler, Level::Debug, "app", &[("request_id", request_id)]), first);
+            }
+        }
+
+        let context_value = context::with([("request_id", "a")], || keep(&sampler, Level::Debug, "app", &[]));
+        assert_eq!(context_value, keep(&sampler, Level::Debug, "app", &[("request_id", "a")]));
+    }
+
+    // Verifies that random sampling keeps roughly the configured fraction.
+    #[test]
+    fn test_random_rate() {
+        let sampler = Sampler::new(&[SamplingRule { level: None, target: None, rate: 0.1, key: None }]).unwrap();
+
+        let kept = (0..10_000).filter(|_| keep(&sampler, Level::Debug, "app", &[])).count();
+
+        assert!((500..1500).contains(&kept), "kept {} of 10000", kept);
+    }
+
+    // Verifies that a NaN rate is rejected instead of silently dropping or keeping everything.
+    #[test]
+    #[should_panic(expected = "Invalid sampling rate NaN")]
+    fn test_nan_rate() {
+        Sampler::new(&[SamplingRule { level: None, target: None, rate: f64::NAN, key: None }]);
+    }
+}
@@ -0,0 +1,301 @@
+//! Counters about the logger itself, see `logpeek::stats()`.
+//!
+//! # Examples
+//! ```
+//! logpeek::init(Default::default()).unwrap();
+//! log::info!("Started");
+//!
+//! let stats = logpeek::stats();
+//! println!("{} errors logged, {} write errors", stats.records.error, stats.write_errors);
+//! ```
+//!
+//! With `Config::recent_counts` set, the records of the last minutes can be queried as well, e.g. for a health check:
+//! ```
+//! use std::time::Duration;
+//! use logpeek::config::{Config, RecentCounts};
+//!
+//! logpeek::init(Config {
+//!     recent_counts: RecentCounts::Window { window: Duration::from_secs(300), targets: vec!["app::db"] },
+//!     ..Default::default()
+//! }).unwrap();
+//!
+//! let healthy = logpeek::stats::recent(Duration::from_secs(300)).error == 0;
+//! let db_errors = logpeek::stats::recent_for_target("app::db", Duration::from_secs(60)).map(|counts| counts.error);
+//! ```
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use log::Level;
+
+use crate::config::RecentCounts;
+use crate::filter::TargetMatcher;
+
+/// A count per log level.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LevelCounts {
+    pub error: u64,
+    pub warn: u64,
+    pub info: u64,
+    pub debug: u64,
+    pub trace: u64,
+}
+
+impl LevelCounts {
+    pub fn get(&self, level: Level) -> u64 {
+        match level {
+            Level::Error => self.error,
+            Level::Warn => self.warn,
+            Level::Info => self.info,
+            Level::Debug => self.debug,
+            Level::Trace => self.trace,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.error + self.warn + self.info + self.debug + self.trace
+    }
+
+    fn increment(&mut self, level: Level) {
+        match level {
+            Level::Error => self.error += 1,
+            Level::Warn => self.warn += 1,
+            Level::Info => self.info += 1,
+            Level::Debug => self.debug += 1,
+            Level::Trace => self.trace += 1,
+        }
+    }
+
+    fn add(&mut self, other: &LevelCounts) {
+        self.error += other.error;
+        self.warn += other.warn;
+        self.info += other.info;
+        self.debug += other.debug;
+        self.trace += other.trace;
+    }
+}
+
+/// A snapshot of the logger counters, counted since the logger was created.
+///
+/// There is no counter for records dropped by an async queue, since logpeek has none: `LoggingStrategy::Asynchronous`
+/// only buffers the file writes in a `BufWriter` on the logging thread, so nothing is dropped for being too slow.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Records that passed all filters and were written, by level. Summaries and notices from the logger itself aren't counted.
+    pub records: LevelCounts,
+    /// Records that reached the logger but were dropped by the target filters, sampling, message filters,
+    /// the rate limit or duplicate suppression.
+    pub filtered: u64,
+    pub console_bytes: u64,
+    /// Bytes written to the main log file.
+    pub file_bytes: u64,
+    /// Bytes written to the files of routing rules.
+    pub route_file_bytes: u64,
+    pub gelf_bytes: u64,
+    /// Failed writes and sends, including records that went to the `WriteFailurePolicy` fallback.
+    pub write_errors: u64,
+    /// Log files created by `SplitLogFiles`.
+    pub rotations: u64,
+}
+
+/// The live counters of one `Logger`.
+#[derive(Default)]
+pub(crate) struct Counters {
+    records: [AtomicU64; 5],
+    pub filtered: AtomicU64,
+    pub console_bytes: AtomicU64,
+    pub file_bytes: AtomicU64,
+    pub route_file_bytes: AtomicU64,
+    pub gelf_bytes: AtomicU64,
+    pub write_errors: AtomicU64,
+    pub rotations: AtomicU64,
+    pub recent: Option<RecentWindow>,
+}
+
+impl Counters {
+    pub fn new(recent_counts: &RecentCounts) -> Counters {
+        Counters {
+            recent: RecentWindow::new(recent_counts),
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&self, level: Level, target: &str) {
+        increment(&self.records[level as usize - 1]);
+        if let Some(recent) = &self.recent {
+            recent.record(level, target);
+        }
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        Stats {
+            records: LevelCounts {
+                error: load(&self.records[0]),
+                warn: load(&self.records[1]),
+                info: load(&self.records[2]),
+                debug: load(&self.records[3]),
+                trace: load(&self.records[4]),
+            },
+            filtered: load(&self.filtered),
+            console_bytes: load(&self.console_bytes),
+            file_bytes: load(&self.file_bytes),
+            route_file_bytes: load(&self.route_file_bytes),
+            gelf_bytes: load(&self.gelf_bytes),
+            write_errors: load(&self.write_errors),
+            rotations: load(&self.rotations),
+        }
+    }
+}
+
+pub(crate) fn increment(counter: &AtomicU64) {
+    add(counter, 1);
+}
+
+pub(crate) fn add(counter: &AtomicU64, value: u64) {
+    counter.fetch_add(value, Ordering::Relaxed);
+}
+
+struct Bucket {
+    second: u64,
+    counts: LevelCounts,
+    target_counts: Vec<LevelCounts>,
+}
+
+/// Counts per second for the last `window`, see `config::RecentCounts`. Seconds without records have no bucket.
+pub(crate) struct RecentWindow {
+    start: Instant,
+    window_secs: u64,
+    targets: Vec<(&'static str, TargetMatcher)>,
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+impl RecentWindow {
+    pub fn new(recent_counts: &RecentCounts) -> Option<RecentWindow> {
+        let RecentCounts::Window { window, targets } = recent_counts else { return None };
+
+        Some(RecentWindow {
+            start: Instant::now(),
+            window_secs: window.as_secs().max(1),
+            targets: targets.iter().map(|target| (*target, TargetMatcher::new([*target]))).collect(),
+            buckets: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_secs()
+    }
+
+    pub fn record(&self, level: Level, target: &str) {
+        self.record_at(self.now(), level, target);
+    }
+
+    fn record_at(&self, now: u64, level: Level, target: &str) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+
+        while buckets.front().is_some_and(|bucket| bucket.second + self.window_secs <= now) {
+            buckets.pop_front();
+        }
+        if buckets.back().is_none_or(|bucket| bucket.second != now) {
+            buckets.push_back(Bucket {
+                second: now,
+                counts: LevelCounts::default(),
+                target_counts: vec![LevelCounts::default(); self.targets.len()],
+            });
+        }
+
+        let Some(bucket) = buckets.back_mut() else { return };
+        bucket.counts.increment(level);
+        for ((_, matcher), counts) in self.targets.iter().zip(bucket.target_counts.iter_mut()) {
+            if matcher.matches(target) {
+                counts.increment(level);
+            }
+        }
+    }
+
+    /// Sums the buckets of the last `duration`, rounded up to whole seconds and capped at the window.
+    /// `target` selects the counts of one of the configured targets, `None` if it isn't one of them.
+    fn counts_at(&self, now: u64, duration: Duration, target: Option<&str>) -> Option<LevelCounts> {
+        let target_index = match target {
+            Some(target) => Some(self.targets.iter().position(|(pattern, _)| *pattern == target)?),
+            None => None,
+        };
+        let seconds = (duration.as_secs() + u64::from(duration.subsec_nanos() > 0)).min(self.window_secs);
+        let buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+
+        let mut total = LevelCounts::default();
+        for bucket in buckets.iter().filter(|bucket| bucket.second + seconds > now) {
+            match target_index {
+                Some(index) => total.add(&bucket.target_counts[index]),
+                None => total.add(&bucket.counts),
+            }
+        }
+        Some(total)
+    }
+
+    pub fn counts(&self, duration: Duration, target: Option<&str>) -> Option<LevelCounts> {
+        self.counts_at(self.now(), duration, target)
+    }
+}
+
+/// The counters of the logger installed by `init`.
+static REGISTERED: OnceLock<Arc<Counters>> = OnceLock::new();
+
+pub(crate) fn register(counters: Arc<Counters>) {
+    let _ = REGISTERED.set(counters);
+}
+
+pub(crate) fn registered() -> Stats {
+    REGISTERED.get().map(|counters| counters.snapshot()).unwrap_or_default()
+}
+
+/// Returns the records written in the last `duration` by the logger installed by `init`, by level.
+/// The counts have a resolution of one second and `duration` is capped at the configured window.
+/// All counts are zero if `Config::recent_counts` is `Disabled`.
+pub fn recent(duration: Duration) -> LevelCounts {
+    REGISTERED.get()
+        .and_then(|counters| counters.recent.as_ref())
+        .and_then(|recent| recent.counts(duration, None))
+        .unwrap_or_default()
+}
+
+/// Like `recent`, but only counts the records matching `target`, which has to be one of the
+/// `RecentCounts::Window::targets`. Returns `None` for other targets.
+pub fn recent_for_target(target: &str, duration: Duration) -> Option<LevelCounts> {
+    REGISTERED.get()
+        .and_then(|counters| counters.recent.as_ref())
+        .and_then(|recent| recent.counts(duration, Some(target)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verifies that counts expire with the window and are kept per configured target.
+    #[test]
+    fn test_recent_window() {
+        let recent = RecentWindow::new(&RecentCounts::Window {
+            window: Duration::from_secs(60),
+            targets: vec!["app::db"],
+        }).unwrap();
+
+        recent.record_at(0, Level::Error, "app::db::pool");
+        recent.record_at(30, Level::Error, "app::http");
+        recent.record_at(30, Level::Warn, "app::db");
+        recent.record_at(59, Level::Info, "app");
+
+        let last_minute = recent.counts_at(59, Duration::from_secs(60), None).unwrap();
+        assert_eq!((last_minute.error, last_minute.warn, last_minute.info), (2, 1, 1));
+        assert_eq!(recent.counts_at(59, Duration::from_secs(30), None).unwrap().error, 1);
+        assert_eq!(recent.counts_at(60, Duration::from_secs(600), None).unwrap().error, 1);
+
+        let db = recent.counts_at(59, Duration::from_secs(60), Some("app::db")).unwrap();
+        assert_eq!((db.error, db.warn), (1, 1));
+        assert!(recent.counts_at(59, Duration::from_secs(60), Some("app::http")).is_none());
+
+        recent.record_at(200, Level::Error, "app");
+        assert_eq!(recent.buckets.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,390 @@
+//! Compiled line templates, see `config::LineTemplate`.
+use std::fmt::Write;
+
+use crate::config::{self, Config, Escaping};
+use crate::escape::escape;
+use crate::record::{hostname, LogRecord};
+
+/// Builds the layout used by `config::LineTemplate::Default`.
+/// Without any optional fields enabled this is `{time} {level} {target} - {message}`.
+pub(crate) fn default_template(config: &Config) -> String {
+    let mut template = String::from("{time} {level} {target}");
+
+    if config.show_module_path == config::ShowModulePath::True {
+        template.push_str(" {module}");
+    }
+    if config.source_location != config::SourceLocation::Hidden {
+        template.push_str(" {location}");
+    }
+    match config.thread_info {
+        config::ThreadInfo::Hidden => {},
+        config::ThreadInfo::Name => template.push_str(" [{thread}]"),
+        config::ThreadInfo::Id => template.push_str(" [{thread_id}]"),
+        config::ThreadInfo::NameAndId => template.push_str(" [{thread}#{thread_id}]"),
+    }
+    if config.show_process_id == config::ShowProcessId::True {
+        template.push_str(" pid={pid}");
+    }
+
+    template.push_str(" - {message}");
+    template
+}
+
+/// A line template parsed once in `Logger::new` and rendered for every record.
+pub(crate) struct CompiledTemplate {
+    items: Vec<TemplateItem>,
+}
+
+enum TemplateItem {
+    Literal(String),
+    Field(Field, FieldSpec),
+}
+
+enum Field {
+    Time,
+    Level,
+    Target,
+    Message,
+    File,
+    Line,
+    /// `file:line`, or nothing for records without a file
+    Location,
+    ModulePath,
+    ThreadName,
+    ThreadId,
+    ProcessId,
+    Hostname,
+    KeyValues,
+    KeyValue(String),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+struct FieldSpec {
+    fill: char,
+    align: Align,
+    width: Option<usize>,
+    precision: Option<usize>,
+}
+
+impl CompiledTemplate {
+    /// Parses a template such as `{time} [{level:<5}] {thread} {module}:{line} {message}`.
+    ///
+    /// Fields are written as `{name}` or `{name:spec}`, where `spec` follows the `std::fmt` syntax
+    /// `[[fill]align][width][.precision]`. The precision truncates the value to that many characters.
+    /// Literal braces are written as `{{` and `}}`.
+    pub fn parse(template: &str) -> Result<CompiledTemplate, String> {
+        let mut items = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(format!("Unclosed placeholder {{{}", placeholder)),
+                        }
+                    }
+
+                    if !literal.is_empty() {
+                        items.push(TemplateItem::Literal(std::mem::take(&mut literal)));
+                    }
+
+                    let (name, spec) = match placeholder.split_once(':') {
+                        Some((name, spec)) => (name, FieldSpec::parse(spec)?),
+                        None => (placeholder.as_str(), FieldSpec::default()),
+                    };
+                    items.push(TemplateItem::Field(Field::parse(name.trim())?, spec));
+                },
+                '}' => return Err(String::from("Unmatched '}', use '}}' for a literal brace")),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            items.push(TemplateItem::Literal(literal));
+        }
+
+        Ok(CompiledTemplate { items })
+    }
+
+    /// Renders the record into a single line, including the trailing newline.
+    /// The escaping policy is applied to the message, the key-values, the target, the file, the module path and the thread name.
+    pub fn render(&self, record: &LogRecord, escaping: &Escaping) -> String {
+        let mut line = String::with_capacity(128);
+        // An empty field (e.g. a missing timestamp) swallows the space that follows it
+        let mut skip_space = false;
+
+        for item in &self.items {
+            match item {
+                TemplateItem::Literal(text) => {
+                    let text = if skip_space { text.strip_prefix(' ').unwrap_or(text) } else { text };
+                    line.push_str(text);
+                    skip_space = false;
+                },
+                TemplateItem::Field(field, spec) => {
+                    let value = field.value(record, escaping);
+                    skip_space = value.is_empty() && spec.width.is_none();
+                    spec.write(&mut line, &value);
+                },
+            }
+        }
+
+        line.push('\n');
+        line
+    }
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Field, String> {
+        Ok(match name {
+            "time" => Field::Time,
+            "level" => Field::Level,
+            "target" => Field::Target,
+            "message" | "args" => Field::Message,
+            "file" => Field::File,
+            "line" => Field::Line,
+            "location" => Field::Location,
+            "module" | "module_path" => Field::ModulePath,
+            "thread" => Field::ThreadName,
+            "thread_id" => Field::ThreadId,
+            "pid" => Field::ProcessId,
+            "hostname" => Field::Hostname,
+            "kv" => Field::KeyValues,
+            _ => match name.strip_prefix("kv.") {
+                Some(key) if !key.is_empty() => Field::KeyValue(key.to_string()),
+                _ => return Err(format!("Unknown template field '{}'", name)),
+            },
+        })
+    }
+
+    fn value(&self, record: &LogRecord, escaping: &Escaping) -> String {
+        match self {
+            Field::Time => record.time.clone().unwrap_or_default(),
+            Field::Level => record.level.to_string(),
+            Field::Target => escape(&record.target, escaping).into_owned(),
+            Field::Message => escape(&record.message, escaping).into_owned(),
+            Field::File => record.file.as_deref().map(|file| escape(file, escaping).into_owned()).unwrap_or_default(),
+            Field::Line => record.line.map(|line| line.to_string()).unwrap_or_default(),
+            Field::Location => match (&record.file, record.line) {
+                (Some(file), Some(line)) => format!("{}:{}", escape(file, escaping), line),
+                // The `:` tells a file apart from a module path
+                (Some(file), None) => format!("{}:", escape(file, escaping)),
+                (None, _) => String::new(),
+            },
+            Field::ModulePath => record.module_path.as_deref().map(|module_path| escape(module_path, escaping).into_owned()).unwrap_or_default(),
+            Field::ThreadName => record.thread_name.as_deref().map(|name| escape(name, escaping).into_owned()).unwrap_or_else(|| String::from("<unnamed>")),
+            Field::ThreadId => record.thread_id.clone(),
+            Field::ProcessId => record.process_id.to_string(),
+            Field::Hostname => hostname().to_string(),
+            Field::KeyValues => {
+                let mut out = String::new();
+                for (key, value) in &record.key_values {
+                    if !out.is_empty() {
+                        out.push(' ');
+                    }
+                    let _ = write!(out, "{}={}", escape(key, escaping), escape(value, escaping));
+                }
+                out
+            },
+            Field::KeyValue(key) => record.key_values.iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| escape(value, escaping).into_owned())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Default for FieldSpec {
+    fn default() -> Self {
+        FieldSpec {
+            fill: ' ',
+            align: Align::Left,
+            width: None,
+            precision: None,
+        }
+    }
+}
+
+impl FieldSpec {
+    fn parse(spec: &str) -> Result<FieldSpec, String> {
+        let mut result = FieldSpec::default();
+        let chars: Vec<char> = spec.chars().collect();
+        let mut pos = 0;
+
+        let to_align = |c: char| match c {
+            '<' => Some(Align::Left),
+            '>' => Some(Align::Right),
+            '^' => Some(Align::Center),
+            _ => None,
+        };
+
+        if let Some(align) = chars.get(1).and_then(|c| to_align(*c)) {
+            result.fill = chars[0];
+            result.align = align;
+            pos = 2;
+        } else if let Some(align) = chars.first().and_then(|c| to_align(*c)) {
+            result.align = align;
+            pos = 1;
+        }
+
+        let rest: String = chars[pos..].iter().collect();
+        let (width, precision) = match rest.split_once('.') {
+            Some((width, precision)) => (width, Some(precision)),
+            None => (rest.as_str(), None),
+        };
+
+        if !width.is_empty() {
+            result.width = Some(width.parse().map_err(|_| format!("Invalid width in field spec '{}'", spec))?);
+        }
+        if let Some(precision) = precision {
+            result.precision = Some(precision.parse().map_err(|_| format!("Invalid precision in field spec '{}'", spec))?);
+        }
+
+        Ok(result)
+    }
+
+    /// Writes the value truncated to `precision` characters and padded to `width` characters.
+    fn write(&self, out: &mut String, value: &str) {
+        let value = match self.precision {
+            Some(precision) => match value.char_indices().nth(precision) {
+                Some((index, _)) => &value[..index],
+                None => value,
+            },
+            None => value,
+        };
+
+        let padding = self.width.unwrap_or(0).saturating_sub(value.chars().count());
+        let (before, after) = match self.align {
+            Align::Left => (0, padding),
+            Align::Right => (padding, 0),
+            Align::Center => (padding / 2, padding - padding / 2),
+        };
+
+        out.extend(std::iter::repeat_n(self.fill, before));
+        out.push_str(value);
+        out.extend(std::iter::repeat_n(self.fill, after));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A record with every field the templates can show
+    fn record() -> LogRecord {
+        LogRecord {
+            module_path: Some(String::from("app::db")),
+            file: Some(String::from("src/db.rs")),
+            line: Some(42),
+            thread_name: Some(String::from("worker")),
+            thread_id: String::from("7"),
+            process_id: 1234,
+            key_values: vec![(String::from("request_id"), String::from("abc"))],
+            ..LogRecord::test("app::db", "connected")
+        }
+    }
+
+    // Verifies that the default template produces the historical line layout.
+    #[test]
+    fn test_default_template() {
+        let template = CompiledTemplate::parse(&default_template(&Config::default())).unwrap();
+
+        assert_eq!(template.render(&record(), &Escaping::Escape), "2024-01-01T00:00:00Z INFO app::db - connected\n");
+    }
+
+    // Verifies that the default template includes the module path and source location when enabled.
+    #[test]
+    fn test_default_template_location() {
+        let template = CompiledTemplate::parse(&default_template(&Config {
+            source_location: config::SourceLocation::FullPath,
+            show_module_path: config::ShowModulePath::True,
+            ..Default::default()
+        })).unwrap();
+
+        assert_eq!(template.render(&record(), &Escaping::Escape), "2024-01-01T00:00:00Z INFO app::db app::db src/db.rs:42 - connected\n");
+    }
+
+    // Verifies that the default template includes the thread and process information when enabled.
+    #[test]
+    fn test_default_template_thread_info() {
+        let template = CompiledTemplate::parse(&default_template(&Config {
+            thread_info: config::ThreadInfo::NameAndId,
+            show_process_id: config::ShowProcessId::True,
+            ..Default::default()
+        })).unwrap();
+
+        assert_eq!(template.render(&record(), &Escaping::Escape), "2024-01-01T00:00:00Z INFO app::db [worker#7] pid=1234 - connected\n");
+    }
+
+    // Verifies that records without a file, like logpeek's own summaries, don't leave a stray `:` behind.
+    #[test]
+    fn test_default_template_without_file() {
+        let template = CompiledTemplate::parse(&default_template(&Config {
+            source_location: config::SourceLocation::FullPath,
+            thread_info: config::ThreadInfo::NameAndId,
+            ..Default::default()
+        })).unwrap();
+        let record = LogRecord { file: None, line: None, ..record() };
+
+        assert_eq!(template.render(&record, &Escaping::Escape), "2024-01-01T00:00:00Z INFO app::db [worker#7] - connected\n");
+    }
+
+    // Verifies that a missing timestamp does not leave a leading space behind.
+    #[test]
+    fn test_missing_time() {
+        let template = CompiledTemplate::parse(&default_template(&Config::default())).unwrap();
+        let record = LogRecord { time: None, ..record() };
+
+        assert_eq!(template.render(&record, &Escaping::Escape), "INFO app::db - connected\n");
+    }
+
+    // Verifies padding, truncation and the record fields that are not part of the default layout.
+    #[test]
+    fn test_custom_fields() {
+        let template = CompiledTemplate::parse("[{level:<5}] {thread}#{thread_id} {pid} {module:.3}|{file:>10}:{line} {kv} {kv.request_id} {{{message:*^11}}}").unwrap();
+
+        assert_eq!(template.render(&record(), &Escaping::Escape), "[INFO ] worker#7 1234 app| src/db.rs:42 request_id=abc abc {*connected*}\n");
+    }
+
+    // Verifies that the escaping policy also applies to the target, file, module path and thread name.
+    #[test]
+    fn test_escaped_fields() {
+        let template = CompiledTemplate::parse("{target} {module} {file} [{thread}] - {message}").unwrap();
+        let record = LogRecord {
+            target: String::from("app\nINFO forged"),
+            module_path: Some(String::from("app\u{1b}[31m")),
+            file: Some(String::from("src\\db.rs")),
+            thread_name: Some(String::from("worker\r")),
+            ..record()
+        };
+
+        assert_eq!(template.render(&record, &Escaping::Escape), "app\\nINFO forged app\\u{1b}[31m src\\\\db.rs [worker\\r] - connected\n");
+    }
+
+    // Verifies that invalid templates are rejected.
+    #[test]
+    fn test_invalid_templates() {
+        assert!(CompiledTemplate::parse("{nope}").is_err());
+        assert!(CompiledTemplate::parse("{time").is_err());
+        assert!(CompiledTemplate::parse("time}").is_err());
+        assert!(CompiledTemplate::parse("{level:<x}").is_err());
+    }
+}
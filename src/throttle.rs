@@ -0,0 +1,334 @@
+//! Rate limiting and duplicate suppression, see `config::RateLimit` and `config::SuppressDuplicates`.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use time::OffsetDateTime;
+
+use crate::config::RateLimit;
+use crate::record::LogRecord;
+
+/// Buckets that are full again and have nothing to report are dropped once the map grows beyond this.
+/// If that isn't enough, the tenth of the buckets that were used longest ago is dropped as well.
+const MAX_BUCKETS: usize = 10_000;
+
+pub(crate) enum RateDecision {
+    Deny,
+    /// `summary` reports the records with the same key that were denied since the last allowed one, if there were any.
+    Allow { summary: Option<Box<LogRecord>> },
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    suppressed: u64,
+    /// The first denied record since the last allowed one, with the time of the last one. Used for the summary on flush.
+    last_denied: Option<LogRecord>,
+}
+
+/// Token buckets keyed by call site or message.
+pub(crate) struct RateLimiter {
+    per_callsite: bool,
+    burst: f64,
+    per_second: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// # Panics
+    /// This function will panic if `per_second` is NaN, zero or negative.
+    pub fn new(rate_limit: &RateLimit) -> Option<RateLimiter> {
+        let (per_callsite, burst, per_second) = match rate_limit {
+            RateLimit::Disabled => return None,
+            RateLimit::PerCallsite { burst, per_second } => (true, burst, per_second),
+            RateLimit::PerMessage { burst, per_second } => (false, burst, per_second),
+        };
+        if per_second.is_nan() || *per_second <= 0.0 {
+            panic!("Invalid rate limit of {} records per second, it must be greater than 0", per_second);
+        }
+
+        Some(RateLimiter {
+            per_callsite,
+            burst: f64::from(*burst),
+            per_second: *per_second,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn check(&self, record: &LogRecord) -> RateDecision {
+        self.check_at(record, Instant::now())
+    }
+
+    fn check_at(&self, record: &LogRecord, now: Instant) -> RateDecision {
+        let key = if self.per_callsite {
+            format!("{}:{}", record.file.as_deref().unwrap_or(&record.target), record.line.unwrap_or(0))
+        } else {
+            format!("{}\0{}", record.target, record.message)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            let (burst, per_second) = (self.burst, self.per_second);
+            buckets.retain(|_, bucket| {
+                bucket.suppressed > 0 || bucket.tokens + now.duration_since(bucket.last_refill).as_secs_f64() * per_second < burst
+            });
+
+            // Too many keys are in use at once, their pending summaries are lost
+            if buckets.len() >= MAX_BUCKETS {
+                let mut last_used = buckets.values().map(|bucket| bucket.last_refill).collect::<Vec<_>>();
+                let (_, &mut cutoff, _) = last_used.select_nth_unstable(MAX_BUCKETS / 10);
+                buckets.retain(|_, bucket| bucket.last_refill > cutoff);
+            }
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            last_refill: now,
+            suppressed: 0,
+            last_denied: None,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            bucket.suppressed += 1;
+            match &mut bucket.last_denied {
+                Some(last_denied) => {
+                    last_denied.timestamp = record.timestamp;
+                    last_denied.time.clone_from(&record.time);
+                },
+                None => bucket.last_denied = Some(record.clone()),
+            }
+            return RateDecision::Deny;
+        }
+
+        bucket.tokens -= 1.0;
+        let suppressed = std::mem::take(&mut bucket.suppressed);
+        RateDecision::Allow { summary: bucket.last_denied.take().map(|last_denied| Box::new(rate_limit_summary(&last_denied, suppressed))) }
+    }
+
+    /// Returns the summaries for records that were denied since the last allowed one of their key,
+    /// so they aren't lost on flush.
+    pub fn take_summaries(&self) -> Vec<LogRecord> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+
+        buckets.values_mut().filter_map(|bucket| {
+            let suppressed = std::mem::take(&mut bucket.suppressed);
+            bucket.last_denied.take().map(|last_denied| rate_limit_summary(&last_denied, suppressed))
+        }).collect()
+    }
+}
+
+/// The summary for `suppressed` records that were denied by the rate limit, with the level, target and call site of `record`.
+fn rate_limit_summary(record: &LogRecord, suppressed: u64) -> LogRecord {
+    let message = match suppressed {
+        1 => String::from("1 similar record was dropped by the rate limit"),
+        _ => format!("{} similar records were dropped by the rate limit", suppressed),
+    };
+    summary_record(record, message)
+}
+
+struct LastRecord {
+    record: LogRecord,
+    repeated: u64,
+    /// When the last duplicate was logged.
+    last_timestamp: OffsetDateTime,
+    last_time: Option<String>,
+}
+
+/// Collapses consecutive identical records.
+#[derive(Default)]
+pub(crate) struct DuplicateSuppressor {
+    last: Mutex<Option<LastRecord>>,
+}
+
+pub(crate) enum DuplicateDecision {
+    Duplicate,
+    /// The record differs from the previous one. Contains the summary for the previous burst of duplicates, if any.
+    New(Option<Box<LogRecord>>),
+}
+
+impl DuplicateSuppressor {
+    pub fn check(&self, record: &LogRecord) -> DuplicateDecision {
+        let mut last = self.last.lock().unwrap_or_else(|err| err.into_inner());
+
+        if let Some(last) = last.as_mut() {
+            if last.record.level == record.level && last.record.target == record.target && last.record.message == record.message {
+                last.repeated += 1;
+                last.last_timestamp = record.timestamp;
+                last.last_time.clone_from(&record.time);
+                return DuplicateDecision::Duplicate;
+            }
+        }
+
+        let previous = last.replace(LastRecord {
+            record: record.clone(),
+            repeated: 0,
+            last_timestamp: record.timestamp,
+            last_time: record.time.clone(),
+        });
+        DuplicateDecision::New(previous.and_then(|previous| repeated_summary(&previous)).map(Box::new))
+    }
+
+    /// Returns the summary for a burst of duplicates that hasn't ended yet, so it isn't lost on flush.
+    pub fn take_summary(&self) -> Option<LogRecord> {
+        let mut last = self.last.lock().unwrap_or_else(|err| err.into_inner());
+
+        last.as_mut().and_then(|last| {
+            let summary = repeated_summary(last);
+            last.repeated = 0;
+            summary
+        })
+    }
+}
+
+/// The summary for a burst of duplicates, logged at the time of the last duplicate.
+fn repeated_summary(last: &LastRecord) -> Option<LogRecord> {
+    let message = match last.repeated {
+        0 => return None,
+        1 => String::from("Previous message repeated once"),
+        repeated => format!("Previous message repeated {} times", repeated),
+    };
+
+    Some(LogRecord {
+        timestamp: last.last_timestamp,
+        time: last.last_time.clone(),
+        ..summary_record(&last.record, message)
+    })
+}
+
+/// A record with the level, target and call site of `record` but a different message.
+fn summary_record(record: &LogRecord, message: String) -> LogRecord {
+    LogRecord {
+        message,
+        key_values: Vec::new(),
+        ..record.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use time::OffsetDateTime;
+    use super::*;
+
+    // Verifies that the bucket allows a burst, denies beyond it and reports the suppressed records after refilling,
+    // dated by the last of them.
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(&RateLimit::PerCallsite { burst: 2, per_second: 1.0 }).unwrap();
+        let start = Instant::now();
+
+        assert!(matches!(limiter.check_at(&LogRecord { line: Some(1), ..LogRecord::test("app", "a") }, start), RateDecision::Allow { summary: None }));
+        assert!(matches!(limiter.check_at(&LogRecord { line: Some(1), ..LogRecord::test("app", "b") }, start), RateDecision::Allow { summary: None }));
+        assert!(matches!(limiter.check_at(&LogRecord { line: Some(1), ..LogRecord::test("app", "c") }, start), RateDecision::Deny));
+        let last_denied = LogRecord {
+            line: Some(1),
+            timestamp: OffsetDateTime::UNIX_EPOCH + Duration::from_secs(5),
+            time: Some(String::from("00:00:05")),
+            ..LogRecord::test("app", "d")
+        };
+        assert!(matches!(limiter.check_at(&last_denied, start), RateDecision::Deny));
+        // A different call site has its own bucket
+        assert!(matches!(limiter.check_at(&LogRecord { line: Some(2), ..LogRecord::test("app", "a") }, start), RateDecision::Allow { summary: None }));
+
+        let later = start + Duration::from_secs(1);
+        match limiter.check_at(&LogRecord { line: Some(1), ..LogRecord::test("app", "e") }, later) {
+            RateDecision::Allow { summary: Some(summary) } => {
+                assert_eq!(summary.message, "2 similar records were dropped by the rate limit");
+                assert_eq!(summary.timestamp, last_denied.timestamp);
+                assert_eq!(summary.time, last_denied.time);
+            },
+            _ => panic!("Expected a summary"),
+        }
+    }
+
+    // Verifies that the oldest buckets are dropped when too many keys have something to report.
+    #[test]
+    fn test_bucket_eviction() {
+        let limiter = RateLimiter::new(&RateLimit::PerMessage { burst: 1, per_second: 0.001 }).unwrap();
+        let start = Instant::now();
+
+        for index in 0..=MAX_BUCKETS {
+            let now = start + Duration::from_millis(index as u64);
+            let record = LogRecord::test("app", &index.to_string());
+            limiter.check_at(&record, now);
+            limiter.check_at(&record, now);
+        }
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.len() < MAX_BUCKETS);
+        assert!(!buckets.contains_key("app\u{0}0"));
+        assert!(buckets.contains_key(&format!("app\u{0}{}", MAX_BUCKETS)));
+    }
+
+    // Verifies that a rate that would never refill the buckets is rejected.
+    #[test]
+    fn test_invalid_rate() {
+        for per_second in [f64::NAN, 0.0, -1.0] {
+            let result = std::panic::catch_unwind(|| RateLimiter::new(&RateLimit::PerCallsite { burst: 1, per_second }));
+            assert!(result.is_err(), "{} was accepted", per_second);
+        }
+    }
+
+    // Verifies that records denied since the last allowed one are summarized on flush.
+    #[test]
+    fn test_rate_limit_summaries() {
+        let limiter = RateLimiter::new(&RateLimit::PerCallsite { burst: 1, per_second: 0.1 }).unwrap();
+        let start = Instant::now();
+
+        for (line, message) in [(1, "a"), (1, "b"), (2, "a"), (2, "b"), (2, "c"), (3, "a")] {
+            limiter.check_at(&LogRecord { line: Some(line), ..LogRecord::test("app", message) }, start);
+        }
+
+        let mut summaries = limiter.take_summaries().into_iter().map(|summary| (summary.line, summary.message)).collect::<Vec<_>>();
+        summaries.sort();
+        assert_eq!(summaries, [
+            (Some(1), String::from("1 similar record was dropped by the rate limit")),
+            (Some(2), String::from("2 similar records were dropped by the rate limit")),
+        ]);
+        assert!(limiter.take_summaries().is_empty());
+    }
+
+    // Verifies that per message limiting ignores the call site.
+    #[test]
+    fn test_per_message() {
+        let limiter = RateLimiter::new(&RateLimit::PerMessage { burst: 1, per_second: 0.1 }).unwrap();
+        let start = Instant::now();
+
+        assert!(matches!(limiter.check_at(&LogRecord { line: Some(1), ..LogRecord::test("app", "same") }, start), RateDecision::Allow { .. }));
+        assert!(matches!(limiter.check_at(&LogRecord { line: Some(2), ..LogRecord::test("app", "same") }, start), RateDecision::Deny));
+        assert!(matches!(limiter.check_at(&LogRecord { line: Some(2), ..LogRecord::test("app", "other") }, start), RateDecision::Allow { .. }));
+    }
+
+    // Verifies that consecutive duplicates are collapsed and summarized once the burst ends.
+    #[test]
+    fn test_duplicate_suppression() {
+        let suppressor = DuplicateSuppressor::default();
+
+        assert!(matches!(suppressor.check(&LogRecord { line: Some(1), ..LogRecord::test("app", "boom") }), DuplicateDecision::New(None)));
+        assert!(matches!(suppressor.check(&LogRecord { line: Some(1), ..LogRecord::test("app", "boom") }), DuplicateDecision::Duplicate));
+        let last_duplicate = LogRecord {
+            timestamp: OffsetDateTime::UNIX_EPOCH + Duration::from_secs(5),
+            time: Some(String::from("00:00:05")),
+            line: Some(1),
+            ..LogRecord::test("app", "boom")
+        };
+        assert!(matches!(suppressor.check(&last_duplicate), DuplicateDecision::Duplicate));
+
+        match suppressor.check(&LogRecord { line: Some(1), ..LogRecord::test("app", "different") }) {
+            DuplicateDecision::New(Some(summary)) => {
+                assert_eq!(summary.message, "Previous message repeated 2 times");
+                assert_eq!(summary.timestamp, last_duplicate.timestamp);
+                assert_eq!(summary.time, last_duplicate.time);
+            },
+            _ => panic!("Expected a summary"),
+        }
+
+        assert!(matches!(suppressor.check(&LogRecord { line: Some(1), ..LogRecord::test("app", "different") }), DuplicateDecision::Duplicate));
+        assert_eq!(suppressor.take_summary().unwrap().message, "Previous message repeated once");
+        assert!(suppressor.take_summary().is_none());
+    }
+}